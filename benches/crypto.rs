@@ -0,0 +1,57 @@
+// Packets per second for sealing and opening one chat packet, comparing the
+// old per-packet password KDF (simple_crypt) with a key derived once.
+//
+//   cargo bench --bench crypto
+
+use std::time::{Duration, Instant};
+
+use hackchat::crypto::RoomCrypto;
+use hackchat::protocol::{Header, OpCode, FLAG_ENCRYPTED};
+
+const ROOM: &str = "benchmark room";
+const PASSPHRASE: &str = "benchmark passphrase";
+
+fn packets_per_second(budget: Duration, mut round_trip: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    let mut packets = 0u64;
+    while start.elapsed() < budget {
+        round_trip();
+        packets += 1;
+    }
+    packets as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let body = vec![0x42; 256];
+    let budget = Duration::from_secs(3);
+
+    let before = packets_per_second(budget, || {
+        let sealed = simple_crypt::encrypt(&body, PASSPHRASE.as_bytes()).unwrap();
+        let opened = simple_crypt::decrypt(&sealed, PASSPHRASE.as_bytes()).unwrap();
+        assert_eq!(opened.len(), body.len());
+    });
+
+    let derive_start = Instant::now();
+    let room = RoomCrypto::derive(ROOM, Some(PASSPHRASE)).unwrap();
+    let derive_time = derive_start.elapsed();
+    assert!(room.is_encrypted());
+    let header = Header::new(
+        FLAG_ENCRYPTED,
+        OpCode::Message,
+        room.room_tag(),
+        RoomCrypto::sealed_len(body.len()),
+    )
+    .to_bytes();
+    let after = packets_per_second(budget, || {
+        let sealed = room.seal(&header, &body).unwrap();
+        assert_eq!(sealed.len(), RoomCrypto::sealed_len(body.len()));
+        let opened = room.open(&header, &sealed).unwrap();
+        assert_eq!(opened.len(), body.len());
+    });
+
+    println!("packet body: {} bytes", body.len());
+    println!("simple_crypt per packet: {before:>12.1} packets/s");
+    println!("room key derived once:   {after:>12.1} packets/s");
+    println!("one-time key derivation: {derive_time:?}");
+    println!("speedup:                 {:>12.1}x", after / before);
+}
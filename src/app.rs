@@ -1,44 +1,98 @@
 use color_eyre::eyre::{Ok, Result};
-use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers};
 use ratatui::layout::Position;
 use ratatui::prelude::Rect;
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::io::Stdout;
-use std::sync::mpsc::channel;
-use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::network::{Op, OpCode};
+use crate::chat::{self, ChatLines, ChatLog, ChatMessage, DirectChat, DirectChats, SendStatus};
+use crate::cli::Args;
+use crate::crypto::RoomCrypto;
+use crate::event::{AppEvent, Events, TICK_RATE};
+use crate::identity::{self, Authenticity, Identity, PublicKey};
+use crate::network::{
+    BroadcastTarget, DropCounters, Ipv6Config, MulticastConfig, NetworkConfig, NetworkHandle,
+    OnlineUser, RoomBrowser, Transport, UnverifiedPolicy,
+};
+use crate::peers::{self, KnownPeers, Trust};
+use crate::protocol::{Op, Presence, MAX_MESSAGE_LEN};
+use crate::room::{self, Lobby, Room, Rooms};
+use crate::transfer::{self, FileInfo, Side, Transfer, TransferState, Transfers, Upload};
 
 pub struct App {
     pub current_screen: CurrentScreen,
-    pub tx: Option<Sender<Op>>,
+    pub network: Option<NetworkHandle>,
+    pub browser: Option<RoomBrowser>,
+    pub network_errors: Vec<std::io::Error>,
+    pub events: Events,
+    pub next_message_id: u64,
     pub mode: Mode,
     pub username: Option<String>,
-    pub room_name: Option<String>,
     pub chat_input: String,
     pub chat_input_index: usize,
-    pub network_messages: Arc<Mutex<Vec<(String, String)>>>,
-    pub chat_messages: Arc<Mutex<(usize, Vec<String>)>>,
+    pub input_too_long: bool,
+    pub network_messages: ChatLog,
+    pub chat_messages: ChatLines,
+    pub rooms: Rooms,
+    pub room: usize,
+    pub lobby: Lobby,
+    pub announce: bool,
+    pub name_taken: Option<(String, String)>,
+    pub login_error: Option<String>,
+    pub directs: DirectChats,
+    pub transfers: Transfers,
+    pub download_dir: Option<PathBuf>,
+    pub view: usize,
     pub chat_index: usize,
     pub max_chat_index: usize,
     pub exit: bool,
-    pub online_users: Arc<Mutex<HashSet<String>>>,
-    pub follow_chat: bool,
+    pub last_activity: Arc<Mutex<Instant>>,
+    pub dropped: Arc<DropCounters>,
     pub inserting: Inserting,
     pub username_input: String,
     pub room_input: String,
+    pub passphrase_input: String,
     pub username_index: usize,
     pub room_index: usize,
+    pub passphrase_index: usize,
+    pub broadcast_targets: Vec<BroadcastTarget>,
+    pub broadcast_choice: Option<usize>,
+    pub port: u16,
+    pub bind: Ipv4Addr,
+    pub multicast: Option<MulticastConfig>,
+    pub ipv4_enabled: bool,
+    pub ipv6: Option<Ipv6Config>,
+    pub heartbeat: Duration,
+    pub identity_path: Option<PathBuf>,
+    pub identity: Option<Arc<Identity>>,
+    pub unverified: UnverifiedPolicy,
+    pub reliable: bool,
+    pub known_peers_path: Option<PathBuf>,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub peer_index: usize,
 }
 
 #[derive(Clone, Copy)]
 pub enum CurrentScreen {
     Login,
     Main,
+    Join,
+    Fingerprints,
     Quit,
 }
 
+pub enum View {
+    System,
+    Room(Arc<Room>),
+    Direct(Arc<DirectChat>),
+}
+
 #[derive(Clone, Copy)]
 pub enum Mode {
     Main,
@@ -49,32 +103,129 @@ pub enum Mode {
 pub enum Inserting {
     Username,
     Room,
+    Passphrase,
     Chat,
 }
 
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl App {
     pub fn new() -> Self {
         App {
             current_screen: CurrentScreen::Login,
-            tx: None,
+            network: None,
+            browser: None,
+            network_errors: Vec::new(),
+            events: Events::new(TICK_RATE),
+            next_message_id: 0,
             mode: Mode::Main,
             username: None,
-            room_name: None,
             chat_input: String::new(),
             chat_input_index: 0,
+            input_too_long: false,
             network_messages: Arc::new(Mutex::new(Vec::new())),
             chat_messages: Arc::new(Mutex::new((0, Vec::new()))),
+            rooms: Arc::new(Mutex::new(Vec::new())),
+            room: 0,
+            lobby: Arc::new(Mutex::new(HashMap::new())),
+            announce: false,
+            name_taken: None,
+            login_error: None,
+            directs: Arc::new(Mutex::new(Vec::new())),
+            transfers: Arc::new(Mutex::new(Vec::new())),
+            download_dir: transfer::default_dir(),
+            view: 0,
             chat_index: 0,
             max_chat_index: 0,
             exit: false,
-            online_users: Arc::new(Mutex::new(HashSet::new())),
-            follow_chat: false,
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            dropped: Arc::new(DropCounters::default()),
             inserting: Inserting::Username,
             username_input: String::new(),
             room_input: String::new(),
+            passphrase_input: String::new(),
             username_index: 0,
             room_index: 0,
+            passphrase_index: 0,
+            broadcast_targets: crate::network::detect_broadcast_targets(),
+            broadcast_choice: None,
+            port: crate::network::DEFAULT_PORT,
+            bind: Ipv4Addr::UNSPECIFIED,
+            multicast: None,
+            ipv4_enabled: true,
+            ipv6: None,
+            heartbeat: crate::network::DEFAULT_HEARTBEAT,
+            identity_path: identity::default_path(),
+            identity: None,
+            unverified: UnverifiedPolicy::Flag,
+            reliable: false,
+            known_peers_path: peers::default_path(),
+            known_peers: Arc::new(Mutex::new(KnownPeers::default())),
+            peer_index: 0,
+        }
+    }
+
+    pub fn from_args(args: Args) -> Self {
+        let mut app = App::new();
+        app.port = args.port;
+        app.bind = args.bind;
+        if let Some(addr) = args.broadcast {
+            app.broadcast_targets = vec![BroadcastTarget {
+                interface: "--broadcast".to_string(),
+                addr,
+            }];
+            app.broadcast_choice = Some(0);
+        }
+        if let Some(group) = args.multicast {
+            app.multicast = Some(MulticastConfig {
+                group,
+                interface: args.multicast_interface,
+                ttl: args.multicast_ttl,
+                loopback: args.multicast_loop,
+            });
+        }
+        app.ipv6 = args.ipv6.map(|interface| Ipv6Config {
+            group: args.ipv6_group,
+            interface,
+            loopback: args.multicast_loop,
+        });
+        app.ipv4_enabled = !args.no_ipv4;
+        app.heartbeat = Duration::from_secs_f64(args.heartbeat);
+        if args.identity.is_some() {
+            app.identity_path = args.identity;
+        }
+        app.unverified = args.unverified;
+        app.reliable = args.reliable;
+        app.announce = args.announce;
+        if args.known_peers.is_some() {
+            app.known_peers_path = args.known_peers;
+        }
+        if args.downloads.is_some() {
+            app.download_dir = args.downloads;
+        }
+        if let Some(username) = args.username {
+            app.username_index = username.chars().count();
+            app.username_input = username;
+        }
+        if let Some(room) = args.room {
+            app.room_index = room.chars().count();
+            app.room_input = room;
         }
+        if let Some(passphrase) = args.passphrase {
+            app.passphrase_index = passphrase.chars().count();
+            app.passphrase_input = passphrase;
+        }
+        if !args.show_login && !app.username_input.is_empty() && !app.room_input.is_empty() {
+            app.submit_login();
+        }
+        if matches!(app.current_screen, CurrentScreen::Login) {
+            app.start_browser();
+        }
+        app
     }
 
     pub fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
@@ -82,11 +233,33 @@ impl App {
             terminal.draw(|frame| self.ui(frame))?;
             self.handle_events()?;
         }
+        self.save_known_peers();
         Ok(())
     }
 
     fn handle_events(&mut self) -> Result<()> {
-        match event::read()? {
+        let mut next = Some(self.events.next()?);
+        while let Some(event) = next {
+            match event {
+                AppEvent::Terminal(event) => self.handle_terminal_event(event),
+                AppEvent::TerminalError(err) => return Err(err.into()),
+                AppEvent::NameTaken(room) => self.name_taken(room),
+                AppEvent::Tick => self.save_known_peers(),
+                AppEvent::Network => {}
+            }
+            if self.exit {
+                break;
+            }
+            next = self.events.try_next()?;
+        }
+        Ok(())
+    }
+
+    fn handle_terminal_event(&mut self, event: Event) {
+        if let Event::Key(_) = event {
+            *self.last_activity.lock().unwrap() = Instant::now();
+        }
+        match event {
             Event::Key(key) if key.kind == KeyEventKind::Press => match self.current_screen {
                 CurrentScreen::Main => match self.mode {
                     Mode::Main => match key.code {
@@ -94,15 +267,67 @@ impl App {
                         KeyCode::Up => self.scroll_up(),
                         KeyCode::Down => self.scroll_down(),
                         KeyCode::Char(' ') => self.mode = Mode::Inputing,
+                        KeyCode::Tab => self.next_view(),
+                        KeyCode::BackTab => self.previous_view(),
+                        KeyCode::Char(c @ '1'..='9')
+                            if key.modifiers.contains(KeyModifiers::ALT) =>
+                        {
+                            self.select_view(c as usize - '1' as usize)
+                        }
+                        KeyCode::Char('a') => self.toggle_announce(),
+                        KeyCode::Char('j') => {
+                            self.inserting = Inserting::Room;
+                            self.current_screen = CurrentScreen::Join;
+                        }
+                        KeyCode::Char('f') => {
+                            self.peer_index = 0;
+                            self.current_screen = CurrentScreen::Fingerprints;
+                        }
                         _ => {}
                     },
                     Mode::Inputing => match key.code {
                         KeyCode::Char(c) => self.enter_char(c, self.inserting),
+                        KeyCode::Backspace => self.delete_char(self.inserting),
                         KeyCode::Esc => self.mode = Mode::Main,
                         KeyCode::Enter => self.submit_msg(),
                         _ => {}
                     },
                 },
+                CurrentScreen::Join => match key.code {
+                    KeyCode::Esc => {
+                        self.room_input.clear();
+                        self.passphrase_input.clear();
+                        self.reset_cursor(Inserting::Room);
+                        self.reset_cursor(Inserting::Passphrase);
+                        self.inserting = Inserting::Chat;
+                        self.current_screen = CurrentScreen::Main;
+                    }
+                    KeyCode::Tab => match self.inserting {
+                        Inserting::Room => self.inserting = Inserting::Passphrase,
+                        _ => self.inserting = Inserting::Room,
+                    },
+                    KeyCode::Char(c) => self.enter_char(c, self.inserting),
+                    KeyCode::Backspace => self.delete_char(self.inserting),
+                    KeyCode::Left => self.move_cursor_left(self.inserting),
+                    KeyCode::Right => self.move_cursor_right(self.inserting),
+                    KeyCode::Enter if !self.room_input.is_empty() => {
+                        self.inserting = Inserting::Chat;
+                        self.current_screen = CurrentScreen::Main;
+                        self.join_room();
+                    }
+                    _ => {}
+                },
+                CurrentScreen::Fingerprints => match key.code {
+                    KeyCode::Esc => self.current_screen = CurrentScreen::Main,
+                    KeyCode::Up => self.peer_index = self.peer_index.saturating_sub(1),
+                    KeyCode::Down => {
+                        let last = self.fingerprint_peers().len().saturating_sub(1);
+                        self.peer_index = (self.peer_index + 1).min(last);
+                    }
+                    KeyCode::Char('v') => self.mark_selected_peer(true),
+                    KeyCode::Char('u') => self.mark_selected_peer(false),
+                    _ => {}
+                },
                 CurrentScreen::Quit => match key.code {
                     KeyCode::Char('y') => self.exit(),
                     KeyCode::Char('n') => self.current_screen = CurrentScreen::Main,
@@ -110,28 +335,46 @@ impl App {
                     _ => {}
                 },
                 CurrentScreen::Login => match key.code {
+                    KeyCode::Esc if self.network.is_some() => self.keep_name(),
                     KeyCode::Esc => self.exit(),
+                    KeyCode::Tab | KeyCode::Up | KeyCode::Down if self.network.is_some() => {}
                     KeyCode::Tab => self.switch_inserting_mode(),
                     KeyCode::Char(c) => match self.inserting {
                         Inserting::Username => self.enter_char(c, self.inserting),
                         Inserting::Room => self.enter_char(c, self.inserting),
+                        Inserting::Passphrase => self.enter_char(c, self.inserting),
                         Inserting::Chat => {}
                     },
                     KeyCode::Backspace => match self.inserting {
                         Inserting::Username => self.delete_char(self.inserting),
                         Inserting::Room => self.delete_char(self.inserting),
+                        Inserting::Passphrase => self.delete_char(self.inserting),
                         Inserting::Chat => panic!("inserting chat while in login screen"),
                     },
                     KeyCode::Left => match self.inserting {
                         Inserting::Username => self.move_cursor_left(self.inserting),
                         Inserting::Room => self.move_cursor_left(self.inserting),
+                        Inserting::Passphrase => self.move_cursor_left(self.inserting),
                         Inserting::Chat => panic!("inserting chat while in login screen"),
                     },
                     KeyCode::Right => match self.inserting {
                         Inserting::Username => self.move_cursor_right(self.inserting),
                         Inserting::Room => self.move_cursor_right(self.inserting),
+                        Inserting::Passphrase => self.move_cursor_right(self.inserting),
                         Inserting::Chat => panic!("inserting chat while in login screen"),
                     },
+                    KeyCode::Up => match self.inserting {
+                        Inserting::Room if !self.lobby.lock().unwrap().is_empty() => {
+                            self.pick_announced_room(false)
+                        }
+                        _ => self.previous_broadcast_target(),
+                    },
+                    KeyCode::Down => match self.inserting {
+                        Inserting::Room if !self.lobby.lock().unwrap().is_empty() => {
+                            self.pick_announced_room(true)
+                        }
+                        _ => self.next_broadcast_target(),
+                    },
                     KeyCode::Enter => self.submit_login(),
 
                     _ => {}
@@ -139,51 +382,669 @@ impl App {
             },
             _ => {}
         }
-        Ok(())
     }
 
     fn switch_inserting_mode(&mut self) {
         match &self.inserting {
             Inserting::Username => self.inserting = Inserting::Room,
-            Inserting::Room => self.inserting = Inserting::Username,
+            Inserting::Room => self.inserting = Inserting::Passphrase,
+            Inserting::Passphrase => self.inserting = Inserting::Username,
             Inserting::Chat => {}
         }
     }
 
+    fn next_broadcast_target(&mut self) {
+        self.broadcast_choice = match self.broadcast_choice {
+            None if !self.broadcast_targets.is_empty() => Some(0),
+            Some(i) if i + 1 < self.broadcast_targets.len() => Some(i + 1),
+            _ => None,
+        };
+    }
+
+    fn previous_broadcast_target(&mut self) {
+        self.broadcast_choice = match self.broadcast_choice {
+            None => self.broadcast_targets.len().checked_sub(1),
+            Some(0) => None,
+            Some(i) => Some(i - 1),
+        };
+    }
+
+    fn pick_announced_room(&mut self, next: bool) {
+        let rooms = room::announced(&self.lobby);
+        if rooms.is_empty() {
+            return;
+        }
+        let current = rooms.iter().position(|seen| seen.label == self.room_input);
+        let i = match current {
+            None if next => 0,
+            None => rooms.len() - 1,
+            Some(i) if next => (i + 1) % rooms.len(),
+            Some(i) => (i + rooms.len() - 1) % rooms.len(),
+        };
+        self.room_input = rooms[i].label.clone();
+        self.room_index = self.room_input.chars().count();
+    }
+
+    fn start_browser(&mut self) {
+        match crate::network::room_browser(
+            &self.network_config(),
+            self.lobby.clone(),
+            self.events.sender(),
+        ) {
+            std::result::Result::Ok(browser) => self.browser = Some(browser),
+            Err(err) => self.network_errors.push(err),
+        }
+    }
+
+    pub fn transport_label(&self) -> String {
+        if let Some(multicast) = &self.multicast {
+            return format!("Multicast: {} (ttl {})", multicast.group, multicast.ttl);
+        }
+        match self.broadcast_choice {
+            Some(i) => {
+                let target = &self.broadcast_targets[i];
+                format!("Interface: {} ({})", target.interface, target.addr)
+            }
+            None if self.broadcast_targets.is_empty() => {
+                format!("Interface: none detected ({})", Ipv4Addr::BROADCAST)
+            }
+            None => {
+                let names: Vec<&str> = self
+                    .broadcast_targets
+                    .iter()
+                    .map(|t| t.interface.as_str())
+                    .collect();
+                format!("Interface: all ({})", names.join(", "))
+            }
+        }
+    }
+
+    fn selected_broadcast_addrs(&self) -> Vec<Ipv4Addr> {
+        match self.broadcast_choice {
+            Some(i) => vec![self.broadcast_targets[i].addr],
+            None if self.broadcast_targets.is_empty() => vec![Ipv4Addr::BROADCAST],
+            None => self.broadcast_targets.iter().map(|t| t.addr).collect(),
+        }
+    }
+
+    fn network_config(&self) -> NetworkConfig {
+        NetworkConfig {
+            port: self.port,
+            bind: self.bind,
+            ipv4: match &self.multicast {
+                _ if !self.ipv4_enabled => None,
+                Some(multicast) => Some(Transport::Multicast(multicast.clone())),
+                None => Some(Transport::Broadcast(self.selected_broadcast_addrs())),
+            },
+            ipv6: self.ipv6.clone(),
+            heartbeat: self.heartbeat,
+            unverified: self.unverified,
+            reliable: self.reliable,
+        }
+    }
+
     fn submit_login(&mut self) {
+        if self.network.is_some() {
+            return self.rename();
+        }
+        let identity = match self.load_identity() {
+            std::result::Result::Ok(identity) => identity,
+            Err(err) => {
+                self.login_error = Some(format!("failed to load the signing key: {err}"));
+                return;
+            }
+        };
+        if let Some(path) = &self.known_peers_path {
+            match KnownPeers::load(path) {
+                std::result::Result::Ok(known) => *self.known_peers.lock().unwrap() = known,
+                Err(err) => {
+                    self.login_error = Some(format!("failed to load known peers: {err}"));
+                    return;
+                }
+            }
+        }
+        self.login_error = None;
         self.username = Some(self.username_input.clone());
-        self.room_name = Some(self.room_input.clone());
         self.current_screen = CurrentScreen::Main;
-        self.add_user(self.username_input.clone());
         self.inserting = Inserting::Chat;
-        let (tx, rx) = channel::<Op>();
-        self.tx = Some(tx);
+        if let Some(browser) = self.browser.take() {
+            self.network_errors.extend(browser.stop());
+        }
         let arcs = crate::network::Arcs {
-            users: self.online_users.clone(),
-            network_messages: self.network_messages.clone(),
-            chat_messages: self.chat_messages.clone(),
+            rooms: self.rooms.clone(),
+            lobby: self.lobby.clone(),
+            activity: self.last_activity.clone(),
+            events: self.events.sender(),
+            dropped: self.dropped.clone(),
+            known_peers: self.known_peers.clone(),
+            directs: self.directs.clone(),
+            transfers: self.transfers.clone(),
+        };
+        let username = self.username_input.clone();
+        self.identity = Some(identity.clone());
+        match crate::network::udp_manager(username, identity, self.network_config(), arcs) {
+            std::result::Result::Ok(network) => self.network = Some(network),
+            Err(err) => {
+                self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                    "failed to start networking: {err}"
+                ))));
+                self.network_errors.push(err);
+            }
+        }
+        self.join_room();
+    }
+
+    fn join_room(&mut self) {
+        let name = std::mem::take(&mut self.room_input);
+        self.reset_cursor(Inserting::Room);
+        let passphrase = std::mem::take(&mut self.passphrase_input);
+        self.reset_cursor(Inserting::Passphrase);
+        let passphrase = Some(passphrase.as_str()).filter(|p| !p.is_empty());
+        let crypto = match RoomCrypto::derive(&name, passphrase) {
+            std::result::Result::Ok(crypto) => crypto,
+            Err(_) => {
+                self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                    "failed to derive the room key".to_string(),
+                )));
+                return;
+            }
+        };
+        let joined = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|room| room.crypto.room_tag() == crypto.room_tag());
+        if let Some(i) = joined {
+            return self.select_view(i);
+        }
+        let announced = room::announced(&self.lobby);
+        if announced.iter().any(|seen| seen.label == name)
+            && !announced.iter().any(|seen| seen.room == crypto.room_tag())
+        {
+            self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                "no announced room named {name} matches this passphrase"
+            ))));
+        }
+        let room = Arc::new(Room::new(name, crypto));
+        room.announce.store(self.announce, Ordering::Relaxed);
+        if let (Some(username), Some(identity), Some(network)) =
+            (&self.username, &self.identity, &self.network)
+        {
+            add_user(
+                &room,
+                network.instance(),
+                username.clone(),
+                Authenticity::Signed(identity.public_key(), Trust::Verified),
+            );
+        }
+        let i = {
+            let mut rooms = self.rooms.lock().unwrap();
+            rooms.push(room.clone());
+            rooms.len() - 1
+        };
+        self.select_view(i);
+        if let Some(network) = &self.network {
+            network.joined();
+            network.request_history(room);
+        }
+    }
+
+    fn toggle_announce(&mut self) {
+        let View::Room(room) = self.active_view() else {
+            return;
+        };
+        let announce = !room.announce.fetch_xor(true, Ordering::Relaxed);
+        let text = if announce {
+            format!(
+                "announcing {} on the network, the passphrase is never sent",
+                room.name
+            )
+        } else {
+            format!("stopped announcing {}", room.name)
+        };
+        room.chat.append(Arc::new(ChatMessage::system(text)));
+        if let Some(network) = &self.network {
+            network.joined();
+        }
+    }
+
+    fn send_file(&mut self, args: &str) {
+        let View::Room(room) = self.active_view() else {
+            return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                "/send only works in a room tab".to_string(),
+            )));
+        };
+        let Some(network) = &self.network else {
+            return;
+        };
+        // Paths with spaces work as long as the whole argument is the file.
+        let (path, username) = match args.rsplit_once(' ') {
+            Some((path, username)) if !Path::new(args).is_file() => {
+                (path.trim_end(), Some(username))
+            }
+            _ => (args, None),
+        };
+        let recipient = match username {
+            Some(username) => {
+                let members = room.members();
+                let key = members
+                    .iter()
+                    .find(|(name, _)| name == username)
+                    .or_else(|| members.iter().find(|(_, user)| user.username == username))
+                    .and_then(|(_, user)| user.authenticity.key());
+                let Some(key) = key else {
+                    return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                        format!("no signed user named {username} is in this room"),
+                    )));
+                };
+                Some((username.to_string(), key))
+            }
+            None => None,
+        };
+        let opened = transfer::hash_file(Path::new(path))
+            .and_then(|(size, hash)| fs::File::open(path).map(|file| (file, size, hash)));
+        let (file, size, hash) = match opened {
+            std::result::Result::Ok(opened) => opened,
+            Err(err) => {
+                return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                    format!("failed to read {path}: {err}"),
+                )))
+            }
+        };
+        let peer = match &recipient {
+            Some((username, _)) => username.clone(),
+            None => format!("everyone in {}", room.name),
+        };
+        let transfer = Arc::new(Transfer::new(
+            rand::random(),
+            network.instance(),
+            recipient.map(|(_, key)| key),
+            room.clone(),
+            FileInfo {
+                name: transfer::safe_name(path),
+                size,
+                hash,
+            },
+            peer,
+            Side::Upload(Mutex::new(Upload {
+                file,
+                offered: Instant::now(),
+                repeats: 0,
+                streams: HashMap::new(),
+                completed: 0,
+            })),
+        ));
+        self.transfers.lock().unwrap().push(transfer.clone());
+        network.offer_file(&transfer);
+        room.chat.append(Arc::new(ChatMessage::system(format!(
+            "offering {} ({}) to {}",
+            transfer.file.name,
+            transfer::format_size(size),
+            transfer.peer
+        ))));
+    }
+
+    fn accept_file(&mut self, number: &str) {
+        let Some(transfer) = self.offered_file(number) else {
+            return;
+        };
+        if let TransferState::Active | TransferState::Done = transfer.state() {
+            return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                format!("{} is already accepted", transfer.file.name),
+            )));
+        }
+        let (Some(network), Some(dir)) = (&self.network, &self.download_dir) else {
+            return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                "no download directory, pass --downloads".to_string(),
+            )));
+        };
+        let Side::Download(download) = &transfer.side else {
+            return;
+        };
+        let mut download = download.lock().unwrap();
+        if let Err(err) = download.open(dir, &transfer.file) {
+            return transfer.fail(&format!("failed to open {}: {err}", dir.display()));
+        }
+        transfer
+            .progress
+            .store(download.received, Ordering::Relaxed);
+        transfer.set_state(TransferState::Active);
+        network.accept_file(&transfer, download.received);
+        if download.received == transfer.file.size {
+            transfer.complete(&mut download);
+        }
+    }
+
+    fn decline_file(&mut self, number: &str) {
+        let Some(transfer) = self.offered_file(number) else {
+            return;
+        };
+        if let TransferState::Offered | TransferState::Failed = transfer.state() {
+            transfer.set_state(TransferState::Declined);
+            transfer
+                .room
+                .chat
+                .append(Arc::new(ChatMessage::system(format!(
+                    "declined {}",
+                    transfer.file.name
+                ))));
+        }
+    }
+
+    fn offered_file(&mut self, number: &str) -> Option<Arc<Transfer>> {
+        let transfer = number
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| {
+                self.transfers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|transfer| transfer.number == n)
+                    .cloned()
+            })
+            .filter(|transfer| !transfer.is_upload());
+        if transfer.is_none() {
+            self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                "no file offered to you is numbered {number}"
+            ))));
+        }
+        transfer
+    }
+
+    fn leave_room(&mut self) {
+        let View::Room(room) = self.active_view() else {
+            return self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(
+                "/leave only works in a room tab".to_string(),
+            )));
+        };
+        self.rooms
+            .lock()
+            .unwrap()
+            .retain(|joined| !Arc::ptr_eq(joined, &room));
+        if let Some(network) = &self.network {
+            network.leave(room, Op::Leave(self.username.clone().unwrap_or_default()));
+        }
+        self.select_view(self.view.saturating_sub(1));
+    }
+
+    // Back on the login screen to pick another name, the rooms stay joined.
+    fn name_taken(&mut self, room: String) {
+        let Some(username) = self.username.clone() else {
+            return;
+        };
+        self.username_index = username.chars().count();
+        self.username_input = username.clone();
+        self.name_taken = Some((username, room));
+        self.inserting = Inserting::Username;
+        self.mode = Mode::Main;
+        self.current_screen = CurrentScreen::Login;
+    }
+
+    fn rename(&mut self) {
+        let username = self.username_input.clone();
+        if username.is_empty() || Some(&username) == self.username.as_ref() {
+            return self.keep_name();
+        }
+        let Some(network) = &self.network else {
+            return;
         };
-        let room_name = self.room_input.clone();
-        std::thread::spawn(|| crate::network::udp_manager(rx, room_name, arcs));
-        let _ = self
-            .tx
+        let rooms = self.rooms.lock().unwrap().clone();
+        let taken = rooms
+            .iter()
+            .find(|room| room.is_taken(&username, network.instance()));
+        let warned = self
+            .name_taken
+            .as_ref()
+            .is_some_and(|(name, _)| *name == username);
+        if let (Some(room), false) = (taken, warned) {
+            self.name_taken = Some((username, room.name.clone()));
+            return;
+        }
+        network.rename(username.clone());
+        self.username = Some(username);
+        self.keep_name();
+    }
+
+    fn keep_name(&mut self) {
+        self.name_taken = None;
+        self.username_input.clear();
+        self.reset_cursor(Inserting::Username);
+        self.inserting = Inserting::Chat;
+        self.current_screen = CurrentScreen::Main;
+    }
+
+    fn save_known_peers(&mut self) {
+        let unsaved = self.known_peers.lock().unwrap().take_unsaved();
+        if let Some((path, contents)) = unsaved {
+            if let Err(err) = peers::write(&path, &contents) {
+                self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                    "failed to save known peers: {err}"
+                ))));
+            }
+        }
+    }
+
+    fn load_identity(&self) -> std::io::Result<Arc<Identity>> {
+        let path = self
+            .identity_path
             .as_ref()
+            .ok_or_else(|| std::io::Error::other("no config directory, pass --identity"))?;
+        Identity::load_or_create(path).map(Arc::new)
+    }
+
+    pub fn current_room(&self) -> Option<Arc<Room>> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(self.room).or(rooms.last()).cloned()
+    }
+
+    pub fn fingerprint_peers(&self) -> Vec<(String, PublicKey, Trust)> {
+        let rooms = self.rooms.lock().unwrap().clone();
+        let own = self.identity.as_ref().map(|identity| identity.public_key());
+        let mut peers = BTreeMap::new();
+        for room in rooms {
+            for user in room.users.lock().unwrap().values() {
+                match user.authenticity {
+                    Authenticity::Signed(key, _) if Some(key) == own => {}
+                    Authenticity::Signed(key, trust) => {
+                        peers.insert((user.username.clone(), key), trust);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        peers
+            .into_iter()
+            .map(|((username, key), trust)| (username, key, trust))
+            .collect()
+    }
+
+    fn mark_selected_peer(&mut self, verified: bool) {
+        let Some((username, key, _)) = self.fingerprint_peers().into_iter().nth(self.peer_index)
+        else {
+            return;
+        };
+        let saved = self
+            .known_peers
+            .lock()
             .unwrap()
-            .send(Op::User(OpCode::User, self.username_input.clone()));
+            .set_verified(&username, &key, verified);
+        if let Err(err) = saved {
+            self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                "failed to save the key of {username}: {err}"
+            ))));
+        }
+        let trust = if verified {
+            Trust::Verified
+        } else {
+            Trust::Pinned
+        };
+        let rooms = self.rooms.lock().unwrap().clone();
+        for room in rooms {
+            for user in room.users.lock().unwrap().values_mut() {
+                if user.username == username && user.authenticity.key() == Some(key) {
+                    user.authenticity = Authenticity::Signed(key, trust);
+                }
+            }
+        }
+    }
+
+    pub fn view_count(&self) -> usize {
+        self.rooms.lock().unwrap().len() + self.directs.lock().unwrap().len()
+    }
+
+    fn select_view(&mut self, view: usize) {
+        if view < self.view_count() {
+            self.view = view;
+        }
+        if self.view < self.rooms.lock().unwrap().len() {
+            self.room = self.view;
+        }
+        self.chat_index = 0;
+    }
+
+    fn next_view(&mut self) {
+        let views = self.view_count().max(1);
+        self.select_view((self.view + 1) % views);
+    }
+
+    fn previous_view(&mut self) {
+        let views = self.view_count().max(1);
+        self.select_view((self.view + views - 1) % views);
+    }
+
+    pub fn active_view(&self) -> View {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(self.view) {
+            return View::Room(room.clone());
+        }
+        match self.directs.lock().unwrap().get(self.view - rooms.len()) {
+            Some(chat) => View::Direct(chat.clone()),
+            None => View::System,
+        }
+    }
+
+    pub fn active_chat(&self) -> (ChatLog, ChatLines) {
+        match self.active_view() {
+            View::System => (self.network_messages.clone(), self.chat_messages.clone()),
+            View::Room(room) => (room.chat.log.clone(), room.chat.lines.clone()),
+            View::Direct(direct) => (direct.chat.log.clone(), direct.chat.lines.clone()),
+        }
+    }
+
+    fn open_direct(&mut self, username: &str) -> Option<usize> {
+        let rooms = self.rooms.lock().unwrap().clone();
+        let key = self
+            .current_room()
+            .into_iter()
+            .chain(rooms)
+            .find_map(|room| {
+                let members = room.members();
+                let user = members
+                    .iter()
+                    .find(|(name, _)| name == username)
+                    .or_else(|| members.iter().find(|(_, user)| user.username == username));
+                user.and_then(|(_, user)| user.authenticity.key())
+            });
+        let Some(key) = key else {
+            self.add_message_to_networklog_and_chat(Arc::new(ChatMessage::system(format!(
+                "no signed user named {username} is online"
+            ))));
+            return None;
+        };
+        let (i, _) = chat::direct_chat(&self.directs, username, &key);
+        let rooms = self.rooms.lock().unwrap().len();
+        self.select_view(rooms + i);
+        Some(i)
     }
 
     fn submit_msg(&mut self) {
-        self.add_message_to_networklog_and_chat(
-            self.username.as_ref().unwrap().clone(),
-            self.chat_input.clone(),
-        );
-        self.chat_input.clear();
+        let mut text = std::mem::take(&mut self.chat_input);
+        self.input_too_long = false;
         self.reset_cursor(self.inserting);
-        let _ = self.tx.as_ref().unwrap().send(Op::Message(
-            OpCode::Message,
-            self.username.as_ref().unwrap().clone(),
-            self.chat_input.clone(),
-        ));
+        if text.trim() == "/leave" {
+            return self.leave_room();
+        }
+        if let Some(rest) = text.strip_prefix("/send ") {
+            return self.send_file(rest.trim());
+        }
+        if let Some(rest) = text.strip_prefix("/accept ") {
+            return self.accept_file(rest.trim());
+        }
+        if let Some(rest) = text.strip_prefix("/decline ") {
+            return self.decline_file(rest.trim());
+        }
+        if let Some(rest) = text.strip_prefix("/msg ") {
+            let (username, rest) = rest
+                .trim_start()
+                .split_once(' ')
+                .unwrap_or((rest.trim(), ""));
+            if self.open_direct(username).is_none() {
+                return;
+            }
+            text = rest.to_string();
+        }
+        if text.is_empty() {
+            return;
+        }
+        let room = match self.active_view() {
+            View::Room(room) => room,
+            View::Direct(direct) => return self.submit_direct(direct, text),
+            View::System => return,
+        };
+        let message = self.own_message(text);
+        room.chat.append(message.clone());
+        match &self.network {
+            Some(network) => network.send_message(room, message),
+            None => message.set_status(SendStatus::Failed),
+        }
+    }
+
+    // Stamped with our instance like peers will see it, so equal clocks
+    // sort the same everywhere.
+    fn own_message(&mut self, text: String) -> Arc<ChatMessage> {
+        self.next_message_id += 1;
+        let (origin, clock) = self
+            .network
+            .as_ref()
+            .map_or((0, 0), |network| (network.instance(), network.tick()));
+        Arc::new(
+            ChatMessage::with_timestamp(
+                origin,
+                self.next_message_id,
+                SystemTime::now(),
+                self.username.as_ref().unwrap().clone(),
+                text,
+                SendStatus::Pending,
+                Authenticity::Local,
+            )
+            .with_clock(clock),
+        )
+    }
+
+    fn submit_direct(&mut self, direct: Arc<DirectChat>, text: String) {
+        let message = self.own_message(text);
+        direct.chat.append(message.clone());
+        let rooms = self.rooms.lock().unwrap().clone();
+        let room = rooms.into_iter().find(|room| {
+            room.users
+                .lock()
+                .unwrap()
+                .values()
+                .any(|user| user.authenticity.key() == Some(direct.key))
+        });
+        match (&self.network, room) {
+            (Some(network), Some(room)) => network.send_direct(room, message, direct.key),
+            (_, None) => {
+                message.set_status(SendStatus::Failed);
+                direct.chat.append(Arc::new(ChatMessage::system(format!(
+                    "{} is not in any of your rooms",
+                    direct.peer
+                ))));
+            }
+            (None, _) => message.set_status(SendStatus::Failed),
+        }
     }
 
     fn scroll_up(&mut self) {
@@ -204,6 +1065,10 @@ impl App {
                 let cursor_moved_left = self.username_index.saturating_sub(1);
                 self.username_index = self.clamp_cursor(cursor_moved_left, inserting);
             }
+            Inserting::Passphrase => {
+                let cursor_moved_left = self.passphrase_index.saturating_sub(1);
+                self.passphrase_index = self.clamp_cursor(cursor_moved_left, inserting);
+            }
             Inserting::Chat => {
                 let cursor_moved_left = self.chat_input_index.saturating_sub(1);
                 self.chat_input_index = self.clamp_cursor(cursor_moved_left, inserting);
@@ -221,6 +1086,10 @@ impl App {
                 let cursor_moved_right = self.username_index.saturating_add(1);
                 self.username_index = self.clamp_cursor(cursor_moved_right, inserting);
             }
+            Inserting::Passphrase => {
+                let cursor_moved_right = self.passphrase_index.saturating_add(1);
+                self.passphrase_index = self.clamp_cursor(cursor_moved_right, inserting);
+            }
             Inserting::Chat => {
                 let cursor_moved_right = self.chat_input_index.saturating_add(1);
                 self.chat_input_index = self.clamp_cursor(cursor_moved_right, inserting);
@@ -229,12 +1098,12 @@ impl App {
     }
 
     fn clamp_cursor(&self, new_cursor_pos: usize, inserting: Inserting) -> usize {
-        let count;
-        match inserting {
-            Inserting::Username => count = self.username_input.chars().count(),
-            Inserting::Room => count = self.room_input.chars().count(),
-            Inserting::Chat => count = self.chat_input.chars().count(),
-        }
+        let count = match inserting {
+            Inserting::Username => self.username_input.chars().count(),
+            Inserting::Room => self.room_input.chars().count(),
+            Inserting::Passphrase => self.passphrase_input.chars().count(),
+            Inserting::Chat => self.chat_input.chars().count(),
+        };
         new_cursor_pos.clamp(0, count)
     }
 
@@ -247,6 +1116,10 @@ impl App {
             Inserting::Room => {
                 Position::new(input_area.x + self.room_index as u16 + 1, input_area.y + 1)
             }
+            Inserting::Passphrase => Position::new(
+                input_area.x + self.passphrase_index as u16 + 1,
+                input_area.y + 1,
+            ),
             Inserting::Chat => Position::new(
                 input_area.x + self.chat_input_index as u16 + 1,
                 input_area.y + 1,
@@ -258,16 +1131,24 @@ impl App {
         match inserting {
             Inserting::Room => self.room_index = 0,
             Inserting::Username => self.username_index = 0,
+            Inserting::Passphrase => self.passphrase_index = 0,
             Inserting::Chat => self.chat_input_index = 0,
         }
     }
 
     fn enter_char(&mut self, new_char: char, inserting: Inserting) {
+        if let Inserting::Chat = inserting {
+            self.input_too_long = self.chat_input.len() + new_char.len_utf8() > MAX_MESSAGE_LEN;
+            if self.input_too_long {
+                return;
+            }
+        }
         let index = self.byte_index(inserting);
         match inserting {
             Inserting::Room => self.room_input.insert(index, new_char),
             Inserting::Chat => self.chat_input.insert(index, new_char),
             Inserting::Username => self.username_input.insert(index, new_char),
+            Inserting::Passphrase => self.passphrase_input.insert(index, new_char),
         }
         self.move_cursor_right(inserting);
     }
@@ -276,6 +1157,7 @@ impl App {
         let is_not_cursor_leftmost = match inserting {
             Inserting::Room => self.room_index != 0,
             Inserting::Username => self.username_index != 0,
+            Inserting::Passphrase => self.passphrase_index != 0,
             Inserting::Chat => self.chat_input_index != 0,
         };
         if is_not_cursor_leftmost {
@@ -299,7 +1181,20 @@ impl App {
                         before_char_to_delete.chain(after_char_to_delete).collect();
                     self.move_cursor_left(inserting);
                 }
+                Inserting::Passphrase => {
+                    let current_index = self.passphrase_index;
+                    let from_left_to_current_index = current_index - 1;
+                    let before_char_to_delete = self
+                        .passphrase_input
+                        .chars()
+                        .take(from_left_to_current_index);
+                    let after_char_to_delete = self.passphrase_input.chars().skip(current_index);
+                    self.passphrase_input =
+                        before_char_to_delete.chain(after_char_to_delete).collect();
+                    self.move_cursor_left(inserting);
+                }
                 Inserting::Chat => {
+                    self.input_too_long = false;
                     let current_index = self.chat_input_index;
                     let from_left_to_current_index = current_index - 1;
                     let before_char_to_delete =
@@ -332,81 +1227,98 @@ impl App {
                 .map(|(i, _)| i)
                 .nth(self.username_index)
                 .unwrap_or(self.username_input.len()),
+            Inserting::Passphrase => self
+                .passphrase_input
+                .char_indices()
+                .map(|(i, _)| i)
+                .nth(self.passphrase_index)
+                .unwrap_or(self.passphrase_input.len()),
         }
     }
 
     pub fn exit(&mut self) {
+        if let Some(browser) = self.browser.take() {
+            self.network_errors.extend(browser.stop());
+        }
+        if let Some(network) = self.network.take() {
+            let farewell = Op::Leave(self.username.clone().unwrap_or_default());
+            self.network_errors.extend(network.shutdown(farewell));
+        }
         self.exit = true;
     }
 
     pub fn create_lines(&mut self, window_width: usize) {
-        let mut lines: Vec<String> = Vec::new();
-
-        let mut i = 1;
-        for (username, msg) in &*self.network_messages.lock().unwrap() {
-            let formated_msg = format!("|{username}| {msg}");
-
-            let mut line = String::new();
-            let mut m = 0;
-            for c in formated_msg.chars() {
-                if m % window_width == 0 {
-                    if line.len() > 0 {
-                        lines.push(line.clone());
-                    }
-                    line.clear();
-                    line += " ";
-                    line += &i.to_string();
-                    line += " ";
-                    m += line.len();
-                    i += 1;
-                }
-                line.push(c);
-                m += 1;
-            }
-
-            if line.len() > 0 {
-                lines.push(line)
-            }
+        let (log, lines) = self.active_chat();
+        let mut chat = (window_width, Vec::new());
+        for message in &*log.lock().unwrap() {
+            chat::push_lines(&mut chat, message);
         }
+        *lines.lock().unwrap() = chat;
+    }
 
-        self.chat_messages = Arc::new(Mutex::new((window_width, lines)));
+    pub fn add_message_to_networklog_and_chat(&mut self, message: Arc<ChatMessage>) {
+        let (log, lines) = self.active_chat();
+        chat::append(&log, &lines, message);
     }
+}
 
-    pub fn add_message_to_networklog_and_chat(&mut self, username: String, msg: String) {
-        (*self.network_messages.lock().unwrap()).push((username.clone(), msg.clone()));
-        let mut lock = self.chat_messages.lock().unwrap();
-        let mut lines = Vec::new();
-        let mut i = lock.1.len() + 1;
-        let formated_msg = format!("|{username}| {msg}");
+pub fn add_user(room: &Room, instance: u64, username: String, authenticity: Authenticity) {
+    room.users.lock().unwrap().insert(
+        instance,
+        OnlineUser {
+            username,
+            presence: Presence::Active,
+            authenticity,
+        },
+    );
+}
 
-        let mut line = String::new();
-        let mut m = 0;
-        for c in formated_msg.chars() {
-            if m % lock.0 == 0 {
-                if line.len() > 0 {
-                    lines.push(line.clone());
-                }
-                line.clear();
-                line += " ";
-                line += &i.to_string();
-                line += " ";
-                m += line.len();
-                i += 1;
-            }
-            line.push(c);
-            m += 1;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
 
-        if line.len() > 0 {
-            lines.push(line)
-        }
+    fn args(extra: &[&str]) -> Args {
+        let dir = std::env::temp_dir().join(format!("hackchat-app-{}", std::process::id()));
+        let identity = dir.join("identity.key");
+        let known_peers = dir.join("known_peers");
+        let mut argv = vec![
+            "hackchat",
+            "--port",
+            "47399",
+            "--username",
+            "alice",
+            "--room",
+            "lobby",
+            "--identity",
+            identity.to_str().unwrap(),
+            "--known-peers",
+            known_peers.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(extra);
+        Args::try_parse_from(argv).unwrap()
+    }
 
-        for line in lines {
-            lock.1.push(line);
+    #[test]
+    fn from_args_skips_login() {
+        let mut app = App::from_args(args(&[]));
+        assert!(matches!(app.current_screen, CurrentScreen::Main));
+        assert_eq!(app.username.as_deref(), Some("alice"));
+        assert!(app.browser.is_none());
+        if let Some(network) = app.network.take() {
+            network.shutdown(Op::Leave("alice".to_string()));
         }
     }
 
-    pub fn add_user(&mut self, username: String) {
-        self.online_users.lock().unwrap().insert(username);
+    #[test]
+    fn from_args_show_login() {
+        let mut app = App::from_args(args(&["--show-login"]));
+        assert!(matches!(app.current_screen, CurrentScreen::Login));
+        assert!(app.network.is_none());
+        assert_eq!(app.username_input, "alice");
+        assert_eq!(app.room_input, "lobby");
+        if let Some(browser) = app.browser.take() {
+            browser.stop();
+        }
     }
 }
@@ -0,0 +1,410 @@
+use std::{
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::identity::{Authenticity, PublicKey};
+
+pub const SYSTEM_AUTHOR: &str = "hackchat";
+pub const HISTORY_DIVIDER: &str = "── history from peers ──";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendStatus {
+    Pending = 0,
+    Sent = 1,
+    Failed = 2,
+    Received = 3,
+}
+
+pub struct ChatMessage {
+    // Instance id of the sender, also ours on our own messages, and 0 for
+    // system messages.
+    pub origin: u64,
+    pub id: u64,
+    // Lamport clock, 0 leaves the message where it was appended.
+    pub clock: u64,
+    pub author: String,
+    pub text: String,
+    pub timestamp: SystemTime,
+    pub authenticity: Authenticity,
+    status: AtomicU8,
+}
+
+pub struct ChatLine {
+    pub text: String,
+    pub message: Option<Arc<ChatMessage>>,
+}
+
+#[derive(Default)]
+pub struct Conversation {
+    pub log: ChatLog,
+    pub lines: ChatLines,
+    pub read: AtomicUsize,
+}
+
+pub struct DirectChat {
+    pub peer: String,
+    pub key: PublicKey,
+    pub chat: Conversation,
+}
+
+pub type ChatLog = Arc<Mutex<Vec<Arc<ChatMessage>>>>;
+pub type ChatLines = Arc<Mutex<(usize, Vec<ChatLine>)>>;
+pub type DirectChats = Arc<Mutex<Vec<Arc<DirectChat>>>>;
+
+impl ChatMessage {
+    pub fn new(id: u64, author: String, text: String, status: SendStatus) -> Self {
+        ChatMessage::with_timestamp(
+            0,
+            id,
+            SystemTime::now(),
+            author,
+            text,
+            status,
+            Authenticity::Local,
+        )
+    }
+
+    pub fn with_timestamp(
+        origin: u64,
+        id: u64,
+        timestamp: SystemTime,
+        author: String,
+        text: String,
+        status: SendStatus,
+        authenticity: Authenticity,
+    ) -> Self {
+        ChatMessage {
+            origin,
+            id,
+            clock: 0,
+            author,
+            text,
+            timestamp,
+            authenticity,
+            status: AtomicU8::new(status as u8),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn system(text: String) -> Self {
+        ChatMessage::new(0, SYSTEM_AUTHOR.to_string(), text, SendStatus::Received)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.origin == 0 && self.id == 0
+    }
+
+    // Lamport order, with the sender breaking ties between equal clocks.
+    fn order(&self) -> (u64, u64) {
+        (self.clock, self.origin)
+    }
+
+    pub fn unix_millis(&self) -> u64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+
+    pub fn status(&self) -> SendStatus {
+        match self.status.load(Ordering::Acquire) {
+            0 => SendStatus::Pending,
+            1 => SendStatus::Sent,
+            2 => SendStatus::Failed,
+            _ => SendStatus::Received,
+        }
+    }
+
+    pub fn set_status(&self, status: SendStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+}
+
+impl Conversation {
+    pub fn append(&self, message: Arc<ChatMessage>) {
+        append(&self.log, &self.lines, message);
+    }
+
+    pub fn contains(&self, origin: u64, id: u64) -> bool {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .any(|message| message.origin == origin && message.id == id)
+    }
+
+    // Slots messages from peers' logs in above the divider, oldest first,
+    // skipping the ones we already have, and returns how many were new.
+    pub fn merge_history(&self, history: Vec<ChatMessage>) -> usize {
+        let mut log = self.log.lock().unwrap();
+        let mut fresh: Vec<ChatMessage> = Vec::new();
+        for message in history {
+            let known = |seen: &ChatMessage| seen.origin == message.origin && seen.id == message.id;
+            if !log.iter().any(|seen| known(seen)) && !fresh.iter().any(known) {
+                fresh.push(message);
+            }
+        }
+        if fresh.is_empty() {
+            return 0;
+        }
+        let divider = match log
+            .iter()
+            .position(|message| message.is_system() && message.text == HISTORY_DIVIDER)
+        {
+            Some(i) => i,
+            None => {
+                log.insert(
+                    0,
+                    Arc::new(ChatMessage::system(HISTORY_DIVIDER.to_string())),
+                );
+                0
+            }
+        };
+        let added = fresh.len();
+        let mut history: Vec<Arc<ChatMessage>> = log
+            .drain(..divider)
+            .chain(fresh.into_iter().map(Arc::new))
+            .collect();
+        history.sort_by_key(|message| (message.order(), message.timestamp));
+        log.splice(..0, history);
+        let mut lines = self.lines.lock().unwrap();
+        let mut rebuilt = (lines.0, Vec::new());
+        for message in log.iter() {
+            push_lines(&mut rebuilt, message);
+        }
+        *lines = rebuilt;
+        added
+    }
+
+    pub fn unread(&self) -> usize {
+        self.log
+            .lock()
+            .unwrap()
+            .len()
+            .saturating_sub(self.read.load(Ordering::Relaxed))
+    }
+
+    pub fn mark_read(&self) {
+        let len = self.log.lock().unwrap().len();
+        self.read.store(len, Ordering::Relaxed);
+    }
+}
+
+pub fn direct_chat(chats: &DirectChats, peer: &str, key: &PublicKey) -> (usize, Arc<DirectChat>) {
+    let mut chats = chats.lock().unwrap();
+    if let Some(i) = chats.iter().position(|chat| chat.key == *key) {
+        return (i, chats[i].clone());
+    }
+    let chat = Arc::new(DirectChat {
+        peer: peer.to_string(),
+        key: *key,
+        chat: Conversation::default(),
+    });
+    chats.push(chat.clone());
+    (chats.len() - 1, chat)
+}
+
+// Inserts the message in causal order. When that is not the end of the log
+// the lines from there on are numbered again.
+pub fn append(log: &ChatLog, lines: &ChatLines, message: Arc<ChatMessage>) {
+    let mut log = log.lock().unwrap();
+    let at = causal_position(&log, &message);
+    log.insert(at, message);
+    let mut lines = lines.lock().unwrap();
+    if at + 1 < log.len() {
+        let kept = match at.checked_sub(1) {
+            Some(previous) => lines
+                .1
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| line.message.is_some())
+                .nth(previous)
+                .map_or(lines.1.len(), |(i, _)| i + 1),
+            None => 0,
+        };
+        lines.1.truncate(kept);
+    }
+    for message in &log[at..] {
+        push_lines(&mut lines, message);
+    }
+}
+
+// Past the clocked messages that come later, but never past an unclocked one
+// so system notices stay where they were written.
+fn causal_position(log: &[Arc<ChatMessage>], message: &ChatMessage) -> usize {
+    let mut at = log.len();
+    if message.clock == 0 {
+        return at;
+    }
+    while at > 0 && log[at - 1].clock != 0 && log[at - 1].order() > message.order() {
+        at -= 1;
+    }
+    at
+}
+
+pub fn push_lines(chat: &mut (usize, Vec<ChatLine>), message: &Arc<ChatMessage>) {
+    let width = chat.0.max(1);
+    let mut lines = Vec::new();
+    let mut i = chat.1.len() + 1;
+    let formated_msg = format!(
+        "|{}{}| {}",
+        message.author,
+        message.authenticity.mark(),
+        message.text
+    );
+
+    let mut line = String::new();
+    let mut m = 0;
+    for c in formated_msg.chars() {
+        if m % width == 0 {
+            if !line.is_empty() {
+                lines.push(line.clone());
+            }
+            line.clear();
+            line += " ";
+            line += &i.to_string();
+            line += " ";
+            m += line.len();
+            i += 1;
+        }
+        line.push(c);
+        m += 1;
+    }
+
+    if !line.is_empty() {
+        lines.push(line)
+    }
+
+    let last = lines.len().saturating_sub(1);
+    for (n, text) in lines.into_iter().enumerate() {
+        chat.1.push(ChatLine {
+            text,
+            message: (n == last).then(|| message.clone()),
+        });
+    }
+}
+
+pub fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Conversation {
+        let conversation = Conversation::default();
+        conversation.lines.lock().unwrap().0 = 80;
+        conversation
+    }
+
+    fn message(origin: u64, clock: u64, text: &str) -> Arc<ChatMessage> {
+        Arc::new(
+            ChatMessage::with_timestamp(
+                origin,
+                clock,
+                UNIX_EPOCH,
+                "peer".to_string(),
+                text.to_string(),
+                SendStatus::Received,
+                Authenticity::Local,
+            )
+            .with_clock(clock),
+        )
+    }
+
+    fn texts(conversation: &Conversation) -> Vec<String> {
+        let log = conversation.log.lock().unwrap();
+        log.iter().map(|message| message.text.clone()).collect()
+    }
+
+    // The lines kept up incrementally have to match the ones built from the
+    // whole log, numbered from 1 without gaps.
+    fn assert_lines(conversation: &Conversation) {
+        let lines = conversation.lines.lock().unwrap();
+        let mut rebuilt = (lines.0, Vec::new());
+        for message in conversation.log.lock().unwrap().iter() {
+            push_lines(&mut rebuilt, message);
+        }
+        let texts: Vec<&str> = lines.1.iter().map(|line| line.text.as_str()).collect();
+        let expected: Vec<&str> = rebuilt.1.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, expected);
+        for (n, line) in lines.1.iter().enumerate() {
+            assert!(line.text.starts_with(&format!(" {} ", n + 1)));
+        }
+    }
+
+    #[test]
+    fn inserts_in_causal_order() {
+        let conversation = conversation();
+        conversation.append(message(1, 1, "question"));
+        conversation.append(message(1, 4, "later"));
+        conversation.append(message(2, 3, "answer"));
+        conversation.append(message(2, 2, "another answer"));
+        assert_eq!(
+            texts(&conversation),
+            ["question", "another answer", "answer", "later"]
+        );
+        assert_lines(&conversation);
+    }
+
+    #[test]
+    fn renumbers_wrapped_lines() {
+        let conversation = conversation();
+        conversation.lines.lock().unwrap().0 = 12;
+        conversation.append(message(1, 1, "a long first message"));
+        conversation.append(message(1, 3, "a long third message"));
+        conversation.append(message(2, 2, "a long second message"));
+        assert_eq!(
+            texts(&conversation),
+            [
+                "a long first message",
+                "a long second message",
+                "a long third message"
+            ]
+        );
+        assert_lines(&conversation);
+    }
+
+    #[test]
+    fn equal_clocks_sort_by_origin() {
+        let first = conversation();
+        first.append(message(5, 2, "from five"));
+        first.append(message(9, 2, "from nine"));
+        let second = conversation();
+        second.append(message(9, 2, "from nine"));
+        second.append(message(5, 2, "from five"));
+        assert_eq!(texts(&first), texts(&second));
+        assert_eq!(texts(&first), ["from five", "from nine"]);
+        assert_lines(&second);
+    }
+
+    #[test]
+    fn system_messages_are_barriers() {
+        let conversation = conversation();
+        conversation.append(message(1, 5, "before"));
+        conversation.append(Arc::new(ChatMessage::system("joined".to_string())));
+        conversation.append(message(2, 3, "late"));
+        assert_eq!(texts(&conversation), ["before", "joined", "late"]);
+        conversation.append(message(2, 4, "later"));
+        conversation.append(message(3, 4, "tied"));
+        assert_eq!(
+            texts(&conversation),
+            ["before", "joined", "late", "later", "tied"]
+        );
+        conversation.append(message(1, 1, "much earlier"));
+        assert_eq!(
+            texts(&conversation),
+            ["before", "joined", "much earlier", "late", "later", "tied"]
+        );
+        assert_lines(&conversation);
+    }
+}
@@ -0,0 +1,190 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser};
+
+use crate::network::{
+    interface_index, UnverifiedPolicy, DEFAULT_HEARTBEAT, DEFAULT_IPV6_GROUP, DEFAULT_PORT,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Chat through your network using UDP broadcasting!")]
+pub struct Args {
+    #[arg(short, long, default_value_t = DEFAULT_PORT, help = "UDP port used by the room")]
+    pub port: u16,
+
+    #[arg(
+        short,
+        long,
+        help = "Broadcast address to send to instead of the detected ones"
+    )]
+    pub broadcast: Option<Ipv4Addr>,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with = "broadcast",
+        value_parser = parse_multicast_group,
+        help = "Join this IPv4 multicast group instead of broadcasting"
+    )]
+    pub multicast: Option<Ipv4Addr>,
+
+    #[arg(
+        long,
+        default_value_t = Ipv4Addr::UNSPECIFIED,
+        help = "Address of the interface to join the multicast group on"
+    )]
+    pub multicast_interface: Ipv4Addr,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Hop limit of outgoing multicast packets"
+    )]
+    pub multicast_ttl: u32,
+
+    #[arg(
+        long,
+        default_value_t = true,
+        action = ArgAction::Set,
+        help = "Deliver our multicast packets to other clients on this host"
+    )]
+    pub multicast_loop: bool,
+
+    #[arg(
+        long,
+        value_name = "INTERFACE",
+        value_parser = parse_interface,
+        help = "Also join an IPv6 link-local multicast group on this interface"
+    )]
+    pub ipv6: Option<u32>,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_IPV6_GROUP,
+        value_parser = parse_link_local_group,
+        help = "IPv6 link-local multicast group (ff02::/16)"
+    )]
+    pub ipv6_group: Ipv6Addr,
+
+    #[arg(long, requires = "ipv6", help = "Only use the IPv6 transport")]
+    pub no_ipv4: bool,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_HEARTBEAT.as_secs_f64(),
+        value_parser = parse_heartbeat,
+        help = "Seconds between presence announcements"
+    )]
+    pub heartbeat: f64,
+
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED, help = "Local address to bind to")]
+    pub bind: Ipv4Addr,
+
+    #[arg(short, long, help = "Username to log in with")]
+    pub username: Option<String>,
+
+    #[arg(short, long, help = "Room to join")]
+    pub room: Option<String>,
+
+    #[arg(
+        long,
+        env = "HACKCHAT_PASSPHRASE",
+        hide_env_values = true,
+        help = "Passphrase of the room, leave it out to join an unencrypted room"
+    )]
+    pub passphrase: Option<String>,
+
+    #[arg(
+        long,
+        help = "Advertise joined rooms on the network, toggle per room with <a>"
+    )]
+    pub announce: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Signing key file, created on first use [default: <config dir>/hackchat/identity.key]"
+    )]
+    pub identity: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Pinned keys of other users [default: <config dir>/hackchat/known_peers]"
+    )]
+    pub known_peers: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Where accepted files are saved [default: <download dir>/hackchat]"
+    )]
+    pub downloads: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = UnverifiedPolicy::Flag,
+        help = "What to do with unsigned or badly signed packets"
+    )]
+    pub unverified: UnverifiedPolicy,
+
+    #[arg(
+        long,
+        help = "Ask peers to resend lost messages and keep ours around for them"
+    )]
+    pub reliable: bool,
+
+    #[arg(
+        long,
+        help = "Show the login screen even when --username and --room are given"
+    )]
+    pub show_login: bool,
+}
+
+fn parse_multicast_group(s: &str) -> Result<Ipv4Addr, String> {
+    let addr: Ipv4Addr = s.parse().map_err(|e| format!("{e}"))?;
+    if addr.is_multicast() {
+        Ok(addr)
+    } else {
+        Err(format!("{addr} is not a multicast address (224.0.0.0/4)"))
+    }
+}
+
+fn parse_link_local_group(s: &str) -> Result<Ipv6Addr, String> {
+    let addr: Ipv6Addr = s.parse().map_err(|e| format!("{e}"))?;
+    if addr.segments()[0] == 0xff02 {
+        Ok(addr)
+    } else {
+        Err(format!(
+            "{addr} is not a link-local multicast address (ff02::/16)"
+        ))
+    }
+}
+
+fn parse_interface(s: &str) -> Result<u32, String> {
+    interface_index(s).ok_or_else(|| format!("no network interface named {s}"))
+}
+
+fn parse_heartbeat(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.1..=3600.0).contains(&secs) {
+        Ok(secs)
+    } else {
+        Err("heartbeat must be between 0.1 and 3600 seconds".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_local_groups_are_ff02() {
+        assert!(parse_link_local_group("ff02::7312").is_ok());
+        assert!(parse_link_local_group("ff12::7312").is_err());
+        assert!(parse_link_local_group("ff05::7312").is_err());
+        assert!(parse_link_local_group("fe80::1").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+pub const ROOM_TAG_LEN: usize = 8;
+
+const SALT_PREFIX: &[u8] = b"hackchat room key v1:";
+
+pub type RoomTag = [u8; ROOM_TAG_LEN];
+
+pub struct RoomCrypto {
+    cipher: Option<Cipher>,
+    tag: RoomTag,
+}
+
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+#[derive(Debug)]
+pub struct CryptoError;
+
+impl RoomCrypto {
+    // Without a passphrase the room is open: the tag is still derived so
+    // rooms stay apart, but packets travel unencrypted.
+    pub fn derive(room: &str, passphrase: Option<&str>) -> Result<Self, CryptoError> {
+        let mut salt = SALT_PREFIX.to_vec();
+        salt.extend_from_slice(room.as_bytes());
+        let mut secret = [0; KEY_LEN + ROOM_TAG_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.unwrap_or("").as_bytes(), &salt, &mut secret)
+            .map_err(|_| CryptoError)?;
+        let (key, tag) = secret.split_at(KEY_LEN);
+        Ok(RoomCrypto {
+            cipher: passphrase.map(|_| Cipher::new(key.try_into().unwrap())),
+            tag: tag.try_into().unwrap(),
+        })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    // The tag is the tail of the Argon2 output, so it identifies the room
+    // without revealing its name or anything about the cipher key.
+    pub fn room_tag(&self) -> RoomTag {
+        self.tag
+    }
+
+    pub fn sealed_len(plaintext_len: usize) -> usize {
+        NONCE_LEN + plaintext_len + TAG_LEN
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.cipher
+            .as_ref()
+            .ok_or(CryptoError)?
+            .seal(aad, plaintext)
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.cipher.as_ref().ok_or(CryptoError)?.open(aad, sealed)
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Cipher {
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| CryptoError)?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| CryptoError)
+    }
+}
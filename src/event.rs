@@ -0,0 +1,72 @@
+use std::{
+    io,
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    time::Duration,
+};
+
+use ratatui::crossterm::event::{self, Event};
+
+pub const TICK_RATE: Duration = Duration::from_secs(1);
+
+pub enum AppEvent {
+    Terminal(Event),
+    TerminalError(io::Error),
+    Network,
+    // Someone in the room had our name before us.
+    NameTaken(String),
+    Tick,
+}
+
+pub struct Events {
+    tx: Sender<AppEvent>,
+    rx: Receiver<AppEvent>,
+}
+
+impl Events {
+    pub fn new(tick_rate: Duration) -> Self {
+        let (tx, rx) = channel();
+        std::thread::spawn({
+            let tx = tx.clone();
+            move || loop {
+                let event = match event::read() {
+                    Ok(event) => AppEvent::Terminal(event),
+                    Err(err) => AppEvent::TerminalError(err),
+                };
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+        std::thread::spawn({
+            let tx = tx.clone();
+            move || loop {
+                std::thread::sleep(tick_rate);
+                if tx.send(AppEvent::Tick).is_err() {
+                    return;
+                }
+            }
+        });
+        Events { tx, rx }
+    }
+
+    pub fn sender(&self) -> Sender<AppEvent> {
+        self.tx.clone()
+    }
+
+    pub fn next(&self) -> io::Result<AppEvent> {
+        match self.rx.recv() {
+            Ok(AppEvent::TerminalError(err)) => Err(err),
+            Ok(event) => Ok(event),
+            Err(_) => Err(io::Error::other("event channel closed")),
+        }
+    }
+
+    pub fn try_next(&self) -> io::Result<Option<AppEvent>> {
+        match self.rx.try_recv() {
+            Ok(AppEvent::TerminalError(err)) => Err(err),
+            Ok(event) => Ok(Some(event)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("event channel closed")),
+        }
+    }
+}
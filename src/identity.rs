@@ -0,0 +1,145 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::Cipher;
+use crate::peers::Trust;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+const FINGERPRINT_BYTES: usize = 4;
+const DIRECT_KEY_CONTEXT: &[u8] = b"hackchat direct message key v1";
+
+pub type PublicKey = [u8; PUBLIC_KEY_LEN];
+
+pub struct Identity {
+    signing: SigningKey,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Authenticity {
+    Local,
+    Signed(PublicKey, Trust),
+    Unsigned,
+    BadSignature,
+    // Passed on by a signed peer from its history. The author's own
+    // signature is not checked, so any member could have written it.
+    Relayed,
+}
+
+impl Identity {
+    pub fn load_or_create(path: &Path) -> Result<Self, std::io::Error> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let seed: [u8; 32] = bytes.try_into().map_err(|_| {
+                    std::io::Error::other(format!("{} is not an identity key", path.display()))
+                })?;
+                Ok(Identity {
+                    signing: SigningKey::from_bytes(&seed),
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let seed: [u8; 32] = rand::random();
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(path)?.write_all(&seed)?;
+                Ok(Identity {
+                    signing: SigningKey::from_bytes(&seed),
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.signing.sign(message).to_bytes()
+    }
+
+    // Both ends of a conversation reach the same X25519 secret from their
+    // own signing key and the other's public key.
+    pub fn direct_cipher(&self, peer: &PublicKey) -> Option<Cipher> {
+        let shared =
+            VerifyingKey::from_bytes(peer).ok()?.to_montgomery() * self.signing.to_scalar();
+        if shared.to_bytes() == [0; 32] {
+            return None;
+        }
+        let own = self.public_key();
+        let (low, high) = if own < *peer {
+            (&own, peer)
+        } else {
+            (peer, &own)
+        };
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(DIRECT_KEY_CONTEXT)
+            .chain_update(shared.to_bytes())
+            .chain_update(low)
+            .chain_update(high)
+            .finalize()
+            .into();
+        Some(Cipher::new(&key))
+    }
+}
+
+impl Authenticity {
+    pub fn key(&self) -> Option<PublicKey> {
+        match self {
+            Authenticity::Signed(key, _) => Some(*key),
+            _ => None,
+        }
+    }
+
+    pub fn mark(&self) -> String {
+        match self {
+            Authenticity::Local => String::new(),
+            Authenticity::Signed(key, Trust::Verified) => format!(" ✓{}", fingerprint(key)),
+            Authenticity::Signed(key, Trust::Pinned) => format!(" {}", fingerprint(key)),
+            Authenticity::Signed(key, Trust::Changed) => {
+                format!(" !{} (key changed)", fingerprint(key))
+            }
+            Authenticity::Unsigned => " (unsigned)".to_string(),
+            Authenticity::BadSignature => " (bad signature)".to_string(),
+            Authenticity::Relayed => " (relayed, unverified)".to_string(),
+        }
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("hackchat").join("identity.key"))
+}
+
+pub fn verify(key: &PublicKey, message: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    VerifyingKey::from_bytes(key).is_ok_and(|verifying| {
+        verifying
+            .verify_strict(message, &Signature::from_bytes(signature))
+            .is_ok()
+    })
+}
+
+pub fn fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key)[..FINGERPRINT_BYTES]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub fn full_fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key)
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
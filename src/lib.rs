@@ -0,0 +1,13 @@
+pub mod app;
+pub mod chat;
+pub mod cli;
+pub mod crypto;
+pub mod event;
+pub mod identity;
+pub mod network;
+pub mod peers;
+pub mod protocol;
+pub mod room;
+pub mod transfer;
+pub mod tui;
+pub mod ui;
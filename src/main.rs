@@ -1,30 +1,15 @@
-use app::App;
+use clap::Parser;
 use color_eyre::Result;
-use tui::init_panic_hook;
-
-mod app;
-mod network;
-mod tui;
-mod ui;
+use hackchat::app::App;
+use hackchat::cli::Args;
+use hackchat::tui::{self, init_panic_hook};
 
 fn main() -> Result<()> {
+    let args = Args::parse();
     color_eyre::install()?;
     init_panic_hook();
     let mut terminal = tui::init_tui()?;
-    let mut app = App::new();
-
-    app.online_users
-        .lock()
-        .unwrap()
-        .insert("User2137".to_string());
-    app.network_messages
-        .lock()
-        .unwrap()
-        .push(("test".to_string(), "hi :3".to_string()));
-    app.room_name = Some("bajo jajo".to_string());
-    app.username = Some("user2".to_string());
-    app.username_input = "testo".to_string();
-    app.room_input = "roomnamme".to_string();
+    let mut app = App::from_args(args);
 
     let result = app.run(&mut terminal);
     if let Err(err) = tui::restore_tui() {
@@ -33,5 +18,8 @@ fn main() -> Result<()> {
             err
         );
     }
+    for err in &app.network_errors {
+        eprintln!("network error: {}", err);
+    }
     result
 }
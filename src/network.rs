@@ -1,251 +1,2660 @@
 use std::{
     cmp::min,
-    collections::{HashMap, HashSet},
-    net::{Ipv4Addr, SocketAddr, UdpSocket},
-    sync::{mpsc::Receiver, Arc, Mutex},
-    time::{Duration, Instant},
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use simple_crypt::{decrypt, encrypt};
+use if_addrs::IfAddr;
+use socket2::{Domain, Protocol, Socket, Type};
 
-const PORT: u16 = 7312;
-const SIGNATURE: &str = "github.com/InfinityCity18/hackchat";
-const PRESENCE_TIMER: Duration = Duration::from_secs(10);
+use crate::chat::{self, ChatMessage, DirectChats, SendStatus};
+use crate::crypto::RoomCrypto;
+use crate::crypto::RoomTag;
+use crate::event::AppEvent;
+use crate::identity::{self, Authenticity, Identity, PublicKey};
+use crate::peers::{KnownPeers, Trust};
+use crate::protocol::{
+    self, Fragment, Header, HistoryEntry, Op, OpCode, Packet, Presence, FLAG_ENCRYPTED,
+    FLAG_FRAGMENT, FLAG_SIGNED, HEADER_LEN, LOBBY, MAX_DATAGRAM, MAX_MESSAGE_LEN, MAX_REASSEMBLED,
+    SIGNATURE_TRAILER_LEN,
+};
+use crate::room::{self, Announcement, Lobby, Room, Rooms};
+use crate::transfer::{
+    self, Download, FileInfo, Side, Transfer, TransferState, Transfers, Upload, UploadStream,
+    CHUNK_LEN,
+};
+
+pub const DEFAULT_PORT: u16 = 7312;
+pub const DEFAULT_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7312);
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(3);
+const MISSED_HEARTBEATS: u32 = 3;
+const MIN_HEARTBEAT_GAP: Duration = Duration::from_millis(500);
+const IDLE_AFTER: Duration = Duration::from_secs(300);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+const DEDUP_WINDOW: Duration = Duration::from_secs(60);
+const REPLAY_WINDOW: u64 = 64;
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(120);
+const MAX_CLOCK_JUMP: u64 = 1 << 20;
+const RETRANSMIT_BUFFER: usize = 256;
+const MAX_NACKS: u32 = 3;
+const RESEND_WINDOW: Duration = Duration::from_secs(1);
+const HISTORY_LIMIT: u16 = 50;
+const HISTORY_CHUNK: usize = 1024;
+const HISTORY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_HISTORY_REPLIES: usize = 16;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REASSEMBLING: usize = 64;
+const MAX_REASSEMBLING_PER_SENDER: usize = 4;
+const FILE_WINDOW: u64 = 32 * CHUNK_LEN as u64;
+const ACK_EVERY: u32 = 8;
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_FILE_RETRIES: u32 = 10;
+const TRANSFER_TICK: Duration = Duration::from_millis(10);
+const OFFER_TTL: Duration = Duration::from_secs(600);
+const OFFER_REPEATS: u32 = 3;
+const MAX_OFFERS: usize = 32;
+const MAX_OFFERS_PER_PEER: usize = 4;
+
+#[derive(Clone)]
+pub struct NetworkConfig {
+    pub port: u16,
+    pub bind: Ipv4Addr,
+    pub ipv4: Option<Transport>,
+    pub ipv6: Option<Ipv6Config>,
+    pub heartbeat: Duration,
+    pub unverified: UnverifiedPolicy,
+    pub reliable: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnverifiedPolicy {
+    Flag,
+    Drop,
+}
+
+#[derive(Clone)]
+pub enum Transport {
+    Broadcast(Vec<Ipv4Addr>),
+    Multicast(MulticastConfig),
+}
+
+#[derive(Clone)]
+pub struct MulticastConfig {
+    pub group: Ipv4Addr,
+    pub interface: Ipv4Addr,
+    pub ttl: u32,
+    pub loopback: bool,
+}
+
+#[derive(Clone)]
+pub struct Ipv6Config {
+    pub group: Ipv6Addr,
+    pub interface: u32,
+    pub loopback: bool,
+}
+
+#[derive(Clone)]
+pub struct BroadcastTarget {
+    pub interface: String,
+    pub addr: Ipv4Addr,
+}
+
+#[derive(Clone)]
+struct Link {
+    socket: Arc<UdpSocket>,
+    targets: Vec<SocketAddr>,
+}
+
+#[derive(Clone)]
+struct Outgoing {
+    links: Vec<Link>,
+    identity: Arc<Identity>,
+    instance: u64,
+    sequence: Arc<AtomicU64>,
+    sent: Arc<Mutex<SentMessages>>,
+    reliable: bool,
+}
+
+// Our last message number in every room, and with `--reliable` the latest
+// messages themselves so they can be sent again.
+#[derive(Default)]
+struct SentMessages {
+    rooms: HashMap<RoomTag, SentStream>,
+}
+
+#[derive(Default)]
+struct SentStream {
+    last: u64,
+    buffer: VecDeque<Op>,
+}
+
+// The next message number expected from every sender in every room, and the
+// skipped ones with how many times and when they were last asked for.
+#[derive(Default)]
+struct InboundStreams {
+    streams: HashMap<StreamId, InboundStream>,
+}
+
+// Room and sender instance.
+type StreamId = (RoomTag, u64);
+
+struct InboundStream {
+    next: u64,
+    missing: BTreeMap<u64, Missing>,
+    // Gaps starting below this were already reported as lost.
+    reported: u64,
+    last_packet: Instant,
+}
+
+struct Missing {
+    tries: u32,
+    asked: Instant,
+    // First number of the gap this one was skipped in.
+    gap: u64,
+}
+
+// Datagrams that arrived in pieces so far, until the last piece comes in or
+// `REASSEMBLY_TIMEOUT` passes. Pieces only go together when they share the
+// room, the sender and the key that signed them, so nobody else can add to
+// a datagram they saw the id of.
+#[derive(Default)]
+struct Reassembly {
+    pending: HashMap<PieceSet, PartialDatagram>,
+}
 
-pub enum Op {
-    Message(OpCode, String, String),
-    User(OpCode, String),
-    Leave(OpCode, String),
+// Room, sender instance, signing key and fragment id.
+type PieceSet = (RoomTag, u64, Option<PublicKey>, u64);
+
+struct PartialDatagram {
+    pieces: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    size: usize,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct RecentMessages {
+    seen: HashMap<(u64, u64), Instant>,
+}
+
+// History requests we answer once a random delay passes, unless some other
+// peer answers first. Keyed by room and requester.
+#[derive(Default)]
+struct HistoryReplies {
+    pending: HashMap<(RoomTag, u64), PendingReply>,
+}
+
+struct PendingReply {
+    room: Arc<Room>,
+    limit: u16,
+    due: Instant,
+}
+
+// Messages resent to each requester in the current `RESEND_WINDOW`, so a
+// small NACK can not make us send more than the buffer holds every window.
+#[derive(Default)]
+struct ResendBudget {
+    requesters: HashMap<u64, (Instant, usize)>,
+}
+
+// Highest sequence number seen from every instance, with a bitmap of the
+// `REPLAY_WINDOW` numbers below it. Instance ids are chosen by the sender, so
+// windows are kept per signing key, whether or not we trust it, and packets
+// without a valid signature only share a window with each other.
+#[derive(Default)]
+struct ReplayWindows {
+    senders: HashMap<(u64, Option<PublicKey>), SenderWindow>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SequenceCheck {
+    Fresh,
+    // Seen inside the window, like the copy of a datagram from the other link.
+    Repeated,
+    TooOld,
+}
+
+struct SenderWindow {
+    highest: u64,
+    seen: u64,
+    last_packet: Instant,
+}
+
+// Our username and when we took it, the older of two users with the same
+// name keeps it undisturbed.
+struct OwnName {
+    username: String,
+    since: u64,
+}
+
+#[derive(Clone)]
+struct ReceiverContext {
+    identity: Arc<Identity>,
+    arcs: Arcs,
+    instance: u64,
+    name: Arc<Mutex<OwnName>>,
+    clock: Arc<AtomicU64>,
+    outbound: Sender<Outbound>,
+    inbound: Arc<Mutex<InboundStreams>>,
+    reliable: bool,
+    recent: Arc<Mutex<RecentMessages>>,
+    history: Arc<Mutex<HistoryReplies>>,
+    resends: Arc<Mutex<ResendBudget>>,
+    replay: Arc<Mutex<ReplayWindows>>,
+    presence_timeout: Duration,
+    heartbeat_wake: Sender<()>,
+    transfer_wake: Sender<()>,
+    unverified: UnverifiedPolicy,
+}
+
+pub enum Outbound {
+    Op(Arc<Room>, Op),
+    Message(Arc<Room>, Arc<ChatMessage>),
+    Direct(Arc<Room>, Arc<ChatMessage>, PublicKey),
+    Resend(Arc<Room>, Vec<u64>),
+}
+
+pub struct RoomBrowser {
+    shutdown: Shutdown,
+    workers: Vec<JoinHandle<Result<(), std::io::Error>>>,
+}
+
+pub struct NetworkHandle {
+    tx: Sender<Outbound>,
+    rooms: Rooms,
+    instance: u64,
+    name: Arc<Mutex<OwnName>>,
+    clock: Arc<AtomicU64>,
+    shutdown: Shutdown,
+    heartbeat_wake: Sender<()>,
+    transfer_wake: Sender<()>,
+    workers: Vec<JoinHandle<Result<(), std::io::Error>>>,
+    sender: JoinHandle<Result<(), std::io::Error>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+#[derive(Default)]
+pub struct DropCounters {
+    pub malformed: AtomicU64,
+    pub foreign_room: AtomicU64,
+    pub undecryptable: AtomicU64,
+    pub duplicate: AtomicU64,
+    pub unverified: AtomicU64,
+    pub replayed: AtomicU64,
+    pub stale: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct OnlineUser {
+    pub username: String,
+    pub presence: Presence,
+    pub authenticity: Authenticity,
 }
 
+#[derive(Clone)]
 pub struct Arcs {
-    pub users: Arc<Mutex<HashSet<String>>>,
-    pub network_messages: Arc<Mutex<Vec<(String, String)>>>,
-    pub chat_messages: Arc<Mutex<(usize, Vec<String>)>>,
+    pub rooms: Rooms,
+    pub lobby: Lobby,
+    pub activity: Arc<Mutex<Instant>>,
+    pub events: Sender<AppEvent>,
+    pub dropped: Arc<DropCounters>,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub directs: DirectChats,
+    pub transfers: Transfers,
+}
+
+pub fn detect_broadcast_targets() -> Vec<BroadcastTarget> {
+    let mut targets = Vec::new();
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(v) => v,
+        Err(_) => return targets,
+    };
+    for interface in interfaces {
+        if interface.is_loopback() || interface.is_p2p() {
+            continue;
+        }
+        if let IfAddr::V4(v4) = &interface.addr {
+            if v4.prefixlen >= 31 {
+                continue;
+            }
+            targets.push(BroadcastTarget {
+                interface: interface.name.clone(),
+                addr: broadcast_address(v4.ip, v4.netmask),
+            });
+        }
+    }
+    targets
+}
+
+pub fn broadcast_address(ip: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(ip) | !u32::from(netmask))
 }
 
-pub enum OpCode {
-    Message = 0,
-    User = 1,
-    Leave = 2,
+pub fn interface_index(name: &str) -> Option<u32> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .and_then(|interface| interface.index)
 }
 
-impl TryFrom<u8> for OpCode {
-    type Error = ();
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Self::Message),
-            1 => Ok(Self::User),
-            2 => Ok(Self::Leave),
-            _ => Err(()),
+fn ipv4_link(bind: Ipv4Addr, port: u16, transport: Transport) -> Result<Link, std::io::Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((bind, port)).into())?;
+    let socket = UdpSocket::from(socket);
+    let targets = match transport {
+        Transport::Broadcast(addrs) => {
+            socket.set_broadcast(true)?;
+            addrs
+                .into_iter()
+                .map(|addr| SocketAddr::from((addr, port)))
+                .collect()
+        }
+        Transport::Multicast(multicast) => {
+            socket.join_multicast_v4(&multicast.group, &multicast.interface)?;
+            socket.set_multicast_ttl_v4(multicast.ttl)?;
+            socket.set_multicast_loop_v4(multicast.loopback)?;
+            vec![SocketAddr::from((multicast.group, port))]
         }
+    };
+    Ok(Link {
+        socket: Arc::new(socket),
+        targets,
+    })
+}
+
+fn ipv6_link(port: u16, config: Ipv6Config) -> Result<Link, std::io::Error> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    let socket = UdpSocket::from(socket);
+    socket.join_multicast_v6(&config.group, config.interface)?;
+    socket.set_multicast_loop_v6(config.loopback)?;
+    let target = SocketAddrV6::new(config.group, port, 0, config.interface);
+    Ok(Link {
+        socket: Arc::new(socket),
+        targets: vec![SocketAddr::V6(target)],
+    })
+}
+
+fn open_links(config: &NetworkConfig) -> Result<Vec<Link>, std::io::Error> {
+    let mut links = Vec::new();
+    if let Some(transport) = &config.ipv4 {
+        links.push(ipv4_link(config.bind, config.port, transport.clone())?);
+    }
+    if let Some(ipv6) = &config.ipv6 {
+        links.push(ipv6_link(config.port, ipv6.clone())?);
+    }
+    Ok(links)
+}
+
+// Listens for room announcements only, for the login screen before any room
+// has been joined.
+pub fn room_browser(
+    config: &NetworkConfig,
+    lobby: Lobby,
+    events: Sender<AppEvent>,
+) -> Result<RoomBrowser, std::io::Error> {
+    let shutdown = Shutdown::default();
+    let mut workers = Vec::new();
+    for link in open_links(config)? {
+        link.socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+        let lobby = lobby.clone();
+        let events = events.clone();
+        let shutdown = shutdown.clone();
+        let ttl = config.heartbeat * MISSED_HEARTBEATS;
+        workers.push(std::thread::spawn(move || {
+            lobby_receiver(link.socket, lobby, events, ttl, shutdown)
+        }));
     }
+    Ok(RoomBrowser { shutdown, workers })
 }
 
-pub fn udp_manager(rx: Receiver<Op>, room: String, arcs: Arcs) -> Result<(), std::io::Error> {
-    let socket = Arc::new(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?);
-    socket.set_broadcast(true)?;
-    socket.connect(SocketAddr::from((
-        "10.21.37.255".parse::<Ipv4Addr>().unwrap(),
-        PORT,
-    )))?;
+pub fn udp_manager(
+    username: String,
+    identity: Arc<Identity>,
+    config: NetworkConfig,
+    arcs: Arcs,
+) -> Result<NetworkHandle, std::io::Error> {
+    let links = open_links(&config)?;
 
-    let presence_map = Arc::new(Mutex::new(HashMap::<String, Instant>::new()));
+    let instance = rand::random();
+    let name = Arc::new(Mutex::new(OwnName {
+        username,
+        since: unix_millis_now(),
+    }));
+    let clock = Arc::new(AtomicU64::new(0));
+    let inbound = Arc::new(Mutex::new(InboundStreams::default()));
+    let shutdown = Shutdown::default();
+    let (tx, rx) = channel();
+    let (wake_tx, wake_rx) = channel();
+    let (transfer_wake, transfer_wake_rx) = channel();
+    let mut workers = Vec::new();
 
-    let mut read_buf: Vec<u8> = [0; 65536].to_vec();
+    let context = ReceiverContext {
+        identity: identity.clone(),
+        arcs: arcs.clone(),
+        instance,
+        name: name.clone(),
+        clock: clock.clone(),
+        outbound: tx.clone(),
+        inbound: inbound.clone(),
+        reliable: config.reliable,
+        recent: Arc::new(Mutex::new(RecentMessages::default())),
+        history: Arc::new(Mutex::new(HistoryReplies::default())),
+        resends: Arc::new(Mutex::new(ResendBudget::default())),
+        replay: Arc::new(Mutex::new(ReplayWindows::default())),
+        presence_timeout: config.heartbeat * MISSED_HEARTBEATS,
+        heartbeat_wake: wake_tx.clone(),
+        transfer_wake: transfer_wake.clone(),
+        unverified: config.unverified,
+    };
+    for link in &links {
+        link.socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+        let socket = link.socket.clone();
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        workers.push(std::thread::spawn(move || {
+            udp_receiver(socket, context, shutdown)
+        }));
+    }
+    workers.push(std::thread::spawn({
+        let shutdown = shutdown.clone();
+        let rooms = arcs.rooms.clone();
+        let events = arcs.events.clone();
+        move || presence_manager(rooms, events, shutdown)
+    }));
+    let outgoing = Outgoing {
+        links,
+        identity,
+        instance,
+        sequence: Arc::new(AtomicU64::new(0)),
+        sent: Arc::new(Mutex::new(SentMessages::default())),
+        reliable: config.reliable,
+    };
+    workers.push(std::thread::spawn({
+        let outgoing = outgoing.clone();
+        let shutdown = shutdown.clone();
+        let arcs = arcs.clone();
+        let name = name.clone();
+        move || {
+            heartbeat(
+                outgoing,
+                name,
+                inbound,
+                arcs,
+                config.heartbeat,
+                wake_rx,
+                shutdown,
+            )
+        }
+    }));
+    workers.push(std::thread::spawn({
+        let outgoing = outgoing.clone();
+        let transfers = arcs.transfers.clone();
+        let events = arcs.events.clone();
+        let shutdown = shutdown.clone();
+        move || transfer_worker(outgoing, transfers, events, transfer_wake_rx, shutdown)
+    }));
+    let events = arcs.events.clone();
+    let sender = std::thread::spawn(move || udp_sender(outgoing, rx, events));
 
-    let presence_map_clone = presence_map.clone();
-    let users_clone = arcs.users.clone();
-    let room_clone = room.clone();
+    Ok(NetworkHandle {
+        tx,
+        rooms: arcs.rooms,
+        instance,
+        name,
+        clock,
+        shutdown,
+        heartbeat_wake: wake_tx,
+        transfer_wake,
+        workers,
+        sender,
+    })
+}
 
-    std::thread::spawn(|| presence_manager(presence_map_clone, users_clone));
-    std::thread::spawn({
-        let socket = socket.clone();
-        move || udp_sender(socket, rx, room_clone)
-    });
+impl NetworkHandle {
+    pub fn send_message(&self, room: Arc<Room>, message: Arc<ChatMessage>) {
+        let _ = self.tx.send(Outbound::Message(room, message));
+    }
 
-    loop {
-        let amount_read = socket.recv(&mut read_buf)?;
+    pub fn send_direct(&self, room: Arc<Room>, message: Arc<ChatMessage>, recipient: PublicKey) {
+        let _ = self.tx.send(Outbound::Direct(room, message, recipient));
+    }
 
-        if let Some(sign) = read_buf.get(0..SIGNATURE.len()) {
-            if sign != SIGNATURE.as_bytes() {
-                continue;
+    pub fn joined(&self) {
+        let _ = self.heartbeat_wake.send(());
+    }
+
+    pub fn request_history(&self, room: Arc<Room>) {
+        let _ = self.tx.send(Outbound::Op(
+            room,
+            Op::HistoryRequest {
+                limit: HISTORY_LIMIT,
+            },
+        ));
+    }
+
+    // Lamport clock for a message we are about to send, above every clock
+    // received so far.
+    pub fn tick(&self) -> u64 {
+        match self
+            .clock
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clock| {
+                clock.checked_add(1)
+            }) {
+            Ok(previous) => previous + 1,
+            Err(last) => last,
+        }
+    }
+
+    pub fn offer_file(&self, transfer: &Transfer) {
+        let _ = self
+            .tx
+            .send(Outbound::Op(transfer.room.clone(), file_offer(transfer)));
+    }
+
+    pub fn accept_file(&self, transfer: &Transfer, offset: u64) {
+        send_file_ack(&self.tx, transfer, offset, true);
+        let _ = self.transfer_wake.send(());
+    }
+
+    pub fn instance(&self) -> u64 {
+        self.instance
+    }
+
+    // Peers key users by instance, so the next heartbeat replaces the old
+    // name everywhere.
+    pub fn rename(&self, username: String) {
+        *self.name.lock().unwrap() = OwnName {
+            username,
+            since: unix_millis_now(),
+        };
+        let _ = self.heartbeat_wake.send(());
+    }
+
+    pub fn leave(&self, room: Arc<Room>, farewell: Op) {
+        let _ = self.tx.send(Outbound::Op(room, farewell));
+    }
+
+    pub fn shutdown(self, farewell: Op) -> Vec<std::io::Error> {
+        let mut errors = Vec::new();
+        self.shutdown.trigger();
+        let _ = self.heartbeat_wake.send(());
+        let _ = self.transfer_wake.send(());
+        for worker in self.workers {
+            if let Err(err) = join_worker(worker) {
+                errors.push(err);
             }
-        } else {
-            continue;
         }
+        for room in self.rooms.lock().unwrap().iter() {
+            let _ = self.tx.send(Outbound::Op(room.clone(), farewell.clone()));
+        }
+        drop(self.tx);
+        if let Err(err) = join_worker(self.sender) {
+            errors.push(err);
+        }
+        errors
+    }
+}
 
-        if let Some(payload) = read_buf.get(SIGNATURE.len()..amount_read - SIGNATURE.len()) {
-            if let Ok(decrypted) = decrypt(payload, room.as_bytes()) {
-                if let Some(v) = read_buf.get(0) {
-                    if let Ok(opcode) = OpCode::try_from(*v) {
-                        match opcode {
-                            OpCode::Message => {
-                                let mut username = Vec::new();
-                                let mut msg = Vec::new();
-                                let mut username_read = false;
-                                for c in decrypted {
-                                    if c == 0 {
-                                        username_read = true;
-                                        continue;
-                                    }
-                                    if username_read {
-                                        msg.push(c);
-                                    } else {
-                                        username.push(c);
-                                    }
-                                }
-                                let username = match String::from_utf8(username) {
-                                    Ok(s) => s,
-                                    Err(_) => continue,
-                                };
-                                let msg = match String::from_utf8(msg) {
-                                    Ok(s) => s,
-                                    Err(_) => continue,
-                                };
-
-                                arcs.network_messages
-                                    .lock()
-                                    .unwrap()
-                                    .push((username.clone(), msg.clone()));
-                                let mut lock = arcs.chat_messages.lock().unwrap();
-                                let mut lines = Vec::new();
-                                let mut i = lock.1.len() + 1;
-                                let formated_msg = format!("|{username}| {msg}");
-
-                                let mut line = String::new();
-                                let mut m = 0;
-                                for c in formated_msg.chars() {
-                                    if m % lock.0 == 0 {
-                                        if line.len() > 0 {
-                                            lines.push(line.clone());
-                                        }
-                                        line.clear();
-                                        line += " ";
-                                        line += &i.to_string();
-                                        line += " ";
-                                        m += line.len();
-                                        i += 1;
-                                    }
-                                    line.push(c);
-                                    m += 1;
-                                }
-
-                                if line.len() > 0 {
-                                    lines.push(line)
-                                }
-
-                                for line in lines {
-                                    lock.1.push(line);
-                                }
-                            }
-                            OpCode::User => {
-                                let username = match String::from_utf8(decrypted) {
-                                    Ok(s) => s,
-                                    Err(_) => continue,
-                                };
-                                arcs.users.lock().unwrap().insert(username.clone());
-                                presence_map
-                                    .lock()
-                                    .unwrap()
-                                    .insert(username, Instant::now() + PRESENCE_TIMER);
-                            }
-                            OpCode::Leave => {
-                                let username = match String::from_utf8(decrypted) {
-                                    Ok(s) => s,
-                                    Err(_) => continue,
-                                };
-                                arcs.users.lock().unwrap().remove(&username);
-                            }
-                        }
-                    } else {
+fn join_worker(worker: JoinHandle<Result<(), std::io::Error>>) -> Result<(), std::io::Error> {
+    worker
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("network thread panicked")))
+}
+
+impl RoomBrowser {
+    pub fn stop(self) -> Vec<std::io::Error> {
+        self.shutdown.trigger();
+        self.workers
+            .into_iter()
+            .filter_map(|worker| join_worker(worker).err())
+            .collect()
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        *self.state.0.lock().unwrap() = true;
+        self.state.1.notify_all();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let lock = self.state.0.lock().unwrap();
+        let (lock, _) = self
+            .state
+            .1
+            .wait_timeout_while(lock, timeout, |triggered| !*triggered)
+            .unwrap();
+        *lock
+    }
+}
+
+fn udp_receiver(
+    socket: Arc<UdpSocket>,
+    context: ReceiverContext,
+    shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    let arcs = &context.arcs;
+    let mut read_buf: Vec<u8> = [0; 65536].to_vec();
+    let mut reassembly = Reassembly::default();
+
+    while !shutdown.is_triggered() {
+        let due = context.history.lock().unwrap().due();
+        for (requester, reply) in due {
+            for entries in history_chunks(&reply.room, context.instance, reply.limit) {
+                let _ = context.outbound.send(Outbound::Op(
+                    reply.room.clone(),
+                    Op::History { requester, entries },
+                ));
+            }
+        }
+        let amount_read = match socket.recv(&mut read_buf) {
+            Ok(v) => v,
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+
+        let dropped = &arcs.dropped;
+        let mut datagram = &read_buf[..amount_read];
+        let (mut header, mut payload) = match protocol::unframe(datagram) {
+            Ok(v) => v,
+            Err(_) => {
+                dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let reassembled;
+        let mut pieced_by = None;
+        if header.flags & FLAG_FRAGMENT != 0 {
+            if room::find(&arcs.rooms, &header.room).is_none() {
+                dropped.foreign_room.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let (piece, key) = if header.flags & FLAG_SIGNED != 0 {
+                match protocol::split_signature(payload) {
+                    Ok((piece, key, signature)) => {
+                        let signed = &datagram[..HEADER_LEN + piece.len()];
+                        let valid = identity::verify(&key, signed, &signature);
+                        (piece, Some(key).filter(|_| valid))
+                    }
+                    Err(_) => {
+                        dropped.malformed.fetch_add(1, Ordering::Relaxed);
                         continue;
                     }
-                } else {
-                    continue;
                 }
             } else {
+                (payload, None)
+            };
+            if key.is_none() && context.unverified == UnverifiedPolicy::Drop {
+                dropped.unverified.fetch_add(1, Ordering::Relaxed);
                 continue;
             }
-        } else {
+            let fragment = match protocol::read_fragment(piece) {
+                Ok(v) => v,
+                Err(_) => {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            pieced_by = Some((fragment.instance, key));
+            let Some(whole) = reassembly.add(header.room, key, fragment) else {
+                continue;
+            };
+            reassembled = whole;
+            datagram = &reassembled;
+            (header, payload) = match protocol::unframe(datagram) {
+                Ok((inner, payload))
+                    if inner.flags & FLAG_FRAGMENT == 0 && inner.room == header.room =>
+                {
+                    (inner, payload)
+                }
+                _ => {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+        }
+        if header.room == LOBBY {
+            match read_announcement(datagram, &header, payload, context.presence_timeout) {
+                Some(announcement) => room::record(&arcs.lobby, announcement),
+                None => {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            let _ = arcs.events.send(AppEvent::Network);
             continue;
         }
-    }
-}
-
-fn presence_manager(
-    presences: Arc<Mutex<HashMap<String, Instant>>>,
-    users: Arc<Mutex<HashSet<String>>>,
-) {
-    loop {
-        let mut presences_lock = presences.lock().unwrap();
-        let mut users_lock = users.lock().unwrap();
-        let mut min_instant = Instant::now() + Duration::from_secs(10);
-        let mut to_del = Vec::new();
-        for (s, i) in &*presences_lock {
-            min_instant = min(min_instant, *i);
-            if *i < Instant::now() {
-                to_del.push(s.clone());
+        let Some(room) = room::find(&arcs.rooms, &header.room) else {
+            dropped.foreign_room.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        if (header.flags & FLAG_ENCRYPTED != 0) != room.crypto.is_encrypted() {
+            dropped.malformed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let plaintext = if room.crypto.is_encrypted() {
+            match room.crypto.open(&datagram[..HEADER_LEN], payload) {
+                Ok(v) => v,
+                Err(_) => {
+                    dropped.undecryptable.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+        } else {
+            payload.to_vec()
+        };
+        let (body, trailer) = if header.flags & FLAG_SIGNED != 0 {
+            match protocol::split_signature(&plaintext) {
+                Ok((body, key, signature)) => (body, Some((key, signature))),
+                Err(_) => {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+        } else {
+            (&plaintext[..], None)
+        };
+        let packet = match protocol::decode(header.opcode, body) {
+            Ok(v) => v,
+            Err(_) => {
+                dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
+        };
+        if packet.instance == context.instance {
+            continue;
+        }
+        let signer = trailer.and_then(|(key, signature)| {
+            let mut signed = datagram[..HEADER_LEN].to_vec();
+            signed.extend_from_slice(body);
+            identity::verify(&key, &signed, &signature).then_some(key)
+        });
+        if signer.is_none() && context.unverified == UnverifiedPolicy::Drop {
+            dropped.unverified.fetch_add(1, Ordering::Relaxed);
+            continue;
         }
-        for s in to_del {
-            presences_lock.remove(&s);
-            users_lock.remove(&s);
+        if pieced_by.is_some_and(|by| by != (packet.instance, signer)) {
+            dropped.malformed.fetch_add(1, Ordering::Relaxed);
+            continue;
         }
-        drop(presences_lock);
-        drop(users_lock);
-        std::thread::sleep(min_instant - Instant::now());
-    }
-}
+        if unix_millis_now().abs_diff(packet.timestamp) > MAX_CLOCK_SKEW.as_millis() as u64 {
+            dropped.stale.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        match context
+            .replay
+            .lock()
+            .unwrap()
+            .accept(packet.instance, signer, packet.sequence)
+        {
+            SequenceCheck::Fresh => {}
+            SequenceCheck::Repeated => {
+                dropped.duplicate.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            SequenceCheck::TooOld => {
+                dropped.replayed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        }
+        // Only fresh packets get to pin a key or warn about a new one.
+        let authenticity = match (signer, trailer) {
+            (Some(key), _) => {
+                let own = context.name.lock().unwrap().username.clone();
+                let own = (own.as_str(), &context.identity.public_key());
+                let trust =
+                    check_pinned_key(&arcs.known_peers, &room, own, packet.op.username(), &key);
+                Authenticity::Signed(key, trust)
+            }
+            (None, Some(_)) => Authenticity::BadSignature,
+            (None, None) => Authenticity::Unsigned,
+        };
 
-fn udp_sender(socket: Arc<UdpSocket>, rx: Receiver<Op>, room: String) {
-    loop {
-        let msg = rx.recv().unwrap();
-        match msg {
-            Op::User(opcode, username) => {
-                let mut to_send = Vec::new();
-                to_send.extend_from_slice(SIGNATURE.as_bytes());
-                let mut to_encrypt = Vec::new();
-                to_encrypt.push(opcode as u8);
-                to_encrypt.extend_from_slice(username.as_bytes());
-                let encrypted = if let Ok(v) = encrypt(&to_encrypt, room.as_bytes()) {
-                    v
-                } else {
+        match packet.op {
+            Op::Message {
+                id,
+                number,
+                clock,
+                timestamp,
+                author,
+                text,
+            } => {
+                // A retransmission we already have still fills its gap.
+                if context.reliable {
+                    track_stream(&context, &room, packet.instance, number, true);
+                }
+                // The recent window forgets, a copy from history stays.
+                if !context.recent.lock().unwrap().insert(packet.instance, id)
+                    || room.chat.contains(packet.instance, id)
+                {
+                    dropped.duplicate.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let message = ChatMessage::with_timestamp(
+                    packet.instance,
+                    id,
+                    chat::from_unix_millis(timestamp),
+                    room.display_name(packet.instance, &author),
+                    text,
+                    SendStatus::Received,
+                    authenticity,
+                )
+                .with_clock(observe_clock(&context.clock, clock));
+                room.chat.append(Arc::new(message));
+            }
+            Op::User(username, since, presence) => {
+                let mut users = room.users.lock().unwrap();
+                if users
+                    .get(&packet.instance)
+                    .is_some_and(|user| user.authenticity.key() != authenticity.key())
+                {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let name_taken = takes_name(
+                    &context.name.lock().unwrap(),
+                    context.instance,
+                    &username,
+                    (since, packet.instance),
+                    &authenticity,
+                );
+                users.insert(
+                    packet.instance,
+                    OnlineUser {
+                        username,
+                        presence,
+                        authenticity,
+                    },
+                );
+                drop(users);
+                let deadline = Instant::now() + context.presence_timeout;
+                let previous = room
+                    .presence
+                    .lock()
+                    .unwrap()
+                    .insert(packet.instance, deadline);
+                if previous.is_none() {
+                    let _ = context.heartbeat_wake.send(());
+                    if name_taken {
+                        let _ = arcs.events.send(AppEvent::NameTaken(room.name.clone()));
+                    }
+                }
+            }
+            Op::Leave(_) => {
+                let mut users = room.users.lock().unwrap();
+                if users
+                    .get(&packet.instance)
+                    .is_some_and(|user| user.authenticity.key() == authenticity.key())
+                {
+                    room.presence.lock().unwrap().remove(&packet.instance);
+                    users.remove(&packet.instance);
+                }
+            }
+            Op::Direct {
+                id,
+                clock,
+                timestamp,
+                author,
+                recipient,
+                sealed,
+            } => {
+                if recipient != context.identity.public_key() {
+                    continue;
+                }
+                let Some(key) = authenticity.key() else {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
                     continue;
                 };
-                to_send.extend_from_slice(&encrypted);
-                socket.send(&to_send);
-            }
-            Op::Leave(opcode, username) => {
-                let mut to_send = Vec::new();
-                to_send.extend_from_slice(SIGNATURE.as_bytes());
-                let mut to_encrypt = Vec::new();
-                to_encrypt.push(opcode as u8);
-                to_encrypt.extend_from_slice(username.as_bytes());
-                let encrypted = if let Ok(v) = encrypt(&to_encrypt, room.as_bytes()) {
-                    v
-                } else {
+                let text = context
+                    .identity
+                    .direct_cipher(&key)
+                    .and_then(|cipher| cipher.open(&direct_aad(id, timestamp), &sealed).ok())
+                    .and_then(|text| String::from_utf8(text).ok());
+                let Some(text) = text else {
+                    dropped.undecryptable.fetch_add(1, Ordering::Relaxed);
                     continue;
                 };
-                to_send.extend_from_slice(&encrypted);
-                socket.send(&to_send);
-            }
-            Op::Message(opcode, username, msg) => {
-                let mut to_send = Vec::new();
-                to_send.extend_from_slice(SIGNATURE.as_bytes());
-                let mut to_encrypt = Vec::new();
-                to_encrypt.push(opcode as u8);
-                to_encrypt.extend_from_slice(username.as_bytes());
-                to_encrypt.push(0);
-                to_encrypt.extend_from_slice(msg.as_bytes());
-                let encrypted = if let Ok(v) = encrypt(&to_encrypt, room.as_bytes()) {
-                    v
-                } else {
+                if text.len() > MAX_MESSAGE_LEN {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if !context.recent.lock().unwrap().insert(packet.instance, id) {
+                    dropped.duplicate.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let message = ChatMessage::with_timestamp(
+                    packet.instance,
+                    id,
+                    chat::from_unix_millis(timestamp),
+                    author.clone(),
+                    text,
+                    SendStatus::Received,
+                    authenticity,
+                )
+                .with_clock(observe_clock(&context.clock, clock));
+                let (_, direct) = chat::direct_chat(&arcs.directs, &author, &key);
+                direct.chat.append(Arc::new(message));
+            }
+            Op::Nack {
+                sender,
+                mut missing,
+            } => {
+                if !context.reliable || sender != context.instance {
+                    continue;
+                }
+                missing.sort_unstable();
+                missing.dedup();
+                missing.truncate(RETRANSMIT_BUFFER);
+                let granted = context
+                    .resends
+                    .lock()
+                    .unwrap()
+                    .take(packet.instance, missing.len());
+                missing.truncate(granted);
+                if !missing.is_empty() {
+                    let _ = context.outbound.send(Outbound::Resend(room, missing));
+                }
+                continue;
+            }
+            Op::Latest { number } => {
+                if context.reliable {
+                    track_stream(&context, &room, packet.instance, number, false);
+                }
+                continue;
+            }
+            Op::HistoryRequest { limit } => {
+                context
+                    .history
+                    .lock()
+                    .unwrap()
+                    .schedule(&room, packet.instance, limit);
+                continue;
+            }
+            Op::History { requester, entries } => {
+                if requester != context.instance {
+                    context.history.lock().unwrap().answered(&room, requester);
                     continue;
+                }
+                if authenticity.key().is_none() {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let history = {
+                    let mut recent = context.recent.lock().unwrap();
+                    entries
+                        .into_iter()
+                        .filter(|entry| entry.origin != 0 && entry.origin != context.instance)
+                        .map(|entry| {
+                            recent.insert(entry.origin, entry.id);
+                            let clock = observe_clock(&context.clock, entry.clock);
+                            ChatMessage::with_timestamp(
+                                entry.origin,
+                                entry.id,
+                                chat::from_unix_millis(entry.timestamp),
+                                entry.author,
+                                entry.text,
+                                SendStatus::Received,
+                                Authenticity::Relayed,
+                            )
+                            .with_clock(clock)
+                        })
+                        .collect()
                 };
-                to_send.extend_from_slice(&encrypted);
-                socket.send(&to_send);
+                if room.chat.merge_history(history) == 0 {
+                    continue;
+                }
             }
-        }
+            Op::FileOffer {
+                transfer,
+                name,
+                size,
+                hash,
+                recipient,
+            } => {
+                let Some(key) = authenticity.key() else {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if recipient.is_some_and(|recipient| recipient != context.identity.public_key())
+                    || transfer::find(&arcs.transfers, packet.instance, transfer).is_some()
+                {
+                    continue;
+                }
+                let peer = match room.users.lock().unwrap().get(&packet.instance) {
+                    Some(user) => user.username.clone(),
+                    None => identity::fingerprint(&key),
+                };
+                let peer = room.display_name(packet.instance, &peer);
+                let file = FileInfo {
+                    name: transfer::safe_name(&name),
+                    size,
+                    hash,
+                };
+                let text = format!(
+                    "{peer} offers {} ({}) to {}",
+                    file.name,
+                    transfer::format_size(size),
+                    if recipient.is_some() {
+                        "you"
+                    } else {
+                        "the room"
+                    }
+                );
+                let n = {
+                    let mut transfers = arcs.transfers.lock().unwrap();
+                    let pending: Vec<PublicKey> = transfers
+                        .iter()
+                        .filter(|transfer| transfer.state() == TransferState::Offered)
+                        .filter_map(|transfer| match &transfer.side {
+                            Side::Download(download) => Some(download.lock().unwrap().sender_key),
+                            Side::Upload(_) => None,
+                        })
+                        .collect();
+                    if pending.len() >= MAX_OFFERS
+                        || pending.iter().filter(|pending| **pending == key).count()
+                            >= MAX_OFFERS_PER_PEER
+                    {
+                        continue;
+                    }
+                    let offer = Arc::new(Transfer::new(
+                        transfer,
+                        packet.instance,
+                        recipient,
+                        room.clone(),
+                        file,
+                        peer,
+                        Side::Download(Mutex::new(Download::new(key))),
+                    ));
+                    transfers.push(offer.clone());
+                    offer.number
+                };
+                room.chat.append(Arc::new(ChatMessage::system(format!(
+                    "{text}, /accept {n} to download it or /decline {n}"
+                ))));
+            }
+            Op::FileAck {
+                sender,
+                transfer,
+                offset,
+                resend,
+            } => {
+                if sender != context.instance {
+                    continue;
+                }
+                let Some(upload) = transfer::find(&arcs.transfers, sender, transfer) else {
+                    continue;
+                };
+                if upload
+                    .recipient
+                    .is_some_and(|recipient| authenticity.key() != Some(recipient))
+                {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let Side::Upload(state) = &upload.side else {
+                    continue;
+                };
+                let mut state = state.lock().unwrap();
+                // Others in the room may still fetch a file after the first
+                // recipients finished, for as long as the offer lasts.
+                let open = match upload.state() {
+                    TransferState::Offered | TransferState::Active => true,
+                    TransferState::Done => {
+                        upload.recipient.is_none() && state.offered.elapsed() <= OFFER_TTL
+                    }
+                    TransferState::Failed | TransferState::Declined => false,
+                };
+                if !open {
+                    continue;
+                }
+                let size = upload.file.size;
+                let offset = offset.min(size);
+                let stream = state
+                    .streams
+                    .entry(packet.instance)
+                    .or_insert(UploadStream {
+                        acked: offset,
+                        next: offset,
+                        last_ack: Instant::now(),
+                        retries: 0,
+                    });
+                if resend {
+                    stream.next = offset;
+                    stream.acked = offset;
+                } else if offset > stream.acked {
+                    stream.next = stream.next.max(offset);
+                    stream.acked = offset;
+                }
+                stream.last_ack = Instant::now();
+                stream.retries = 0;
+                upload.progress.fetch_max(offset, Ordering::Relaxed);
+                upload.set_state(TransferState::Active);
+                if stream.acked == size {
+                    state.streams.remove(&packet.instance);
+                    state.completed += 1;
+                    if state.streams.is_empty() {
+                        upload.set_state(TransferState::Done);
+                    }
+                } else {
+                    let _ = context.transfer_wake.send(());
+                }
+            }
+            Op::FileChunk {
+                requester,
+                transfer,
+                offset,
+                hash,
+                data,
+            } => {
+                if requester != context.instance {
+                    continue;
+                }
+                let Some(download) = transfer::find(&arcs.transfers, packet.instance, transfer)
+                else {
+                    continue;
+                };
+                let Side::Download(state) = &download.side else {
+                    continue;
+                };
+                if download.state() != TransferState::Active {
+                    continue;
+                }
+                let mut state = state.lock().unwrap();
+                if authenticity.key() != Some(state.sender_key) {
+                    dropped.unverified.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if offset != state.received {
+                    if offset > state.received && !state.gap_reported {
+                        state.gap_reported = true;
+                        send_file_ack(&context.outbound, &download, state.received, true);
+                    }
+                    continue;
+                }
+                let data =
+                    match download.recipient {
+                        Some(_) => {
+                            let opened =
+                                context.identity.direct_cipher(&state.sender_key).and_then(
+                                    |cipher| cipher.open(&file_aad(transfer, offset), &data).ok(),
+                                );
+                            let Some(data) = opened else {
+                                dropped.undecryptable.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            };
+                            data
+                        }
+                        None => data,
+                    };
+                if data.is_empty()
+                    || transfer::chunk_hash(&data) != hash
+                    || offset + data.len() as u64 > download.file.size
+                {
+                    dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if let Err(err) = state.write(&data) {
+                    download.fail(&format!("failed to write: {err}"));
+                } else if state.received == download.file.size {
+                    send_file_ack(&context.outbound, &download, state.received, false);
+                    download.complete(&mut state);
+                } else if state.unacked >= ACK_EVERY {
+                    state.unacked = 0;
+                    send_file_ack(&context.outbound, &download, state.received, false);
+                } else {
+                    download.progress.store(state.received, Ordering::Relaxed);
+                    continue;
+                }
+                download.progress.store(state.received, Ordering::Relaxed);
+            }
+            Op::Announce { .. } => {
+                dropped.malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        }
+        let _ = arcs.events.send(AppEvent::Network);
+    }
+    Ok(())
+}
+
+// The last `limit` delivered messages we can vouch for, in packets of about
+// `HISTORY_CHUNK` bytes. Our own messages are sent under our instance id.
+fn history_chunks(room: &Room, instance: u64, limit: u16) -> Vec<Vec<HistoryEntry>> {
+    let log = room.chat.log.lock().unwrap();
+    let shared: Vec<&Arc<ChatMessage>> = log
+        .iter()
+        .filter(|message| {
+            !message.is_system()
+                && matches!(message.status(), SendStatus::Sent | SendStatus::Received)
+                && matches!(
+                    message.authenticity,
+                    Authenticity::Local | Authenticity::Signed(..) | Authenticity::Relayed
+                )
+        })
+        .collect();
+    let skip = shared
+        .len()
+        .saturating_sub(min(limit, HISTORY_LIMIT) as usize);
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = 0;
+    for message in &shared[skip..] {
+        let entry_size = 3 * 8 + 2 * 2 + message.author.len() + message.text.len();
+        if !chunk.is_empty() && size + entry_size > HISTORY_CHUNK {
+            chunks.push(std::mem::take(&mut chunk));
+            size = 0;
+        }
+        size += entry_size;
+        chunk.push(HistoryEntry {
+            origin: if message.origin == 0 {
+                instance
+            } else {
+                message.origin
+            },
+            id: message.id,
+            clock: message.clock,
+            timestamp: message.unix_millis(),
+            author: message.author.clone(),
+            text: message.text.clone(),
+        });
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+fn file_offer(transfer: &Transfer) -> Op {
+    Op::FileOffer {
+        transfer: transfer.id,
+        name: transfer.file.name.clone(),
+        size: transfer.file.size,
+        hash: transfer.file.hash,
+        recipient: transfer.recipient,
+    }
+}
+
+fn send_file_ack(outbound: &Sender<Outbound>, transfer: &Transfer, offset: u64, resend: bool) {
+    let _ = outbound.send(Outbound::Op(
+        transfer.room.clone(),
+        Op::FileAck {
+            sender: transfer.sender,
+            transfer: transfer.id,
+            offset,
+            resend,
+        },
+    ));
+}
+
+// Streams up to `FILE_WINDOW` bytes past the last acknowledged offset to
+// every recipient and asks again for downloads that went quiet.
+// Sleeps on `wake` until a download is accepted or a recipient acks an
+// upload, then ticks for as long as any stream is open.
+fn transfer_worker(
+    outgoing: Outgoing,
+    transfers: Transfers,
+    events: Sender<AppEvent>,
+    wake: Receiver<()>,
+    shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    let mut open = false;
+    while !shutdown.is_triggered() {
+        let woken = if open {
+            match wake.recv_timeout(TRANSFER_TICK) {
+                Err(RecvTimeoutError::Disconnected) => break,
+                woken => woken.is_ok(),
+            }
+        } else {
+            match wake.recv() {
+                Err(_) => break,
+                Ok(()) => true,
+            }
+        };
+        if woken {
+            while wake.try_recv().is_ok() {}
+        }
+        if shutdown.is_triggered() {
+            break;
+        }
+        let transfers = transfers.lock().unwrap().clone();
+        let mut changed = false;
+        open = false;
+        for transfer in transfers.iter() {
+            match &transfer.side {
+                Side::Upload(upload) => {
+                    if let TransferState::Offered | TransferState::Active = transfer.state() {
+                        let upload = &mut upload.lock().unwrap();
+                        if let Err(err) = send_chunks(&outgoing, transfer, upload) {
+                            transfer.fail(&format!("failed to read: {err}"));
+                            changed = true;
+                        } else if upload.streams.is_empty()
+                            && transfer.state() == TransferState::Active
+                        {
+                            // Every recipient finished or went quiet.
+                            transfer.set_state(if upload.completed > 0 {
+                                TransferState::Done
+                            } else {
+                                TransferState::Offered
+                            });
+                            changed = true;
+                        }
+                        open |= !upload.streams.is_empty();
+                    }
+                }
+                Side::Download(download) => {
+                    if transfer.state() != TransferState::Active {
+                        continue;
+                    }
+                    open = true;
+                    let mut download = download.lock().unwrap();
+                    if download.last_chunk.elapsed() < ACK_TIMEOUT {
+                        continue;
+                    }
+                    download.retries += 1;
+                    download.last_chunk = Instant::now();
+                    if download.retries > MAX_FILE_RETRIES {
+                        transfer.fail(&format!(
+                            "the sender went quiet, /accept {} resumes",
+                            transfer.number
+                        ));
+                        changed = true;
+                    } else {
+                        let ack = Op::FileAck {
+                            sender: transfer.sender,
+                            transfer: transfer.id,
+                            offset: download.received,
+                            resend: true,
+                        };
+                        let _ = outgoing.send(&transfer.room.crypto, ack);
+                    }
+                }
+            }
+        }
+        if changed {
+            let _ = events.send(AppEvent::Network);
+        }
+    }
+    Ok(())
+}
+
+// Drops downloads nobody accepted within the offer's lifetime, the sender
+// has stopped announcing them by then.
+fn expire_offers(transfers: &Transfers) -> Vec<Arc<Transfer>> {
+    let mut expired = Vec::new();
+    transfers.lock().unwrap().retain(|transfer| {
+        let Side::Download(download) = &transfer.side else {
+            return true;
+        };
+        if transfer.state() != TransferState::Offered
+            || download.lock().unwrap().offered.elapsed() <= OFFER_TTL
+        {
+            return true;
+        }
+        expired.push(transfer.clone());
+        false
+    });
+    expired
+}
+
+fn send_chunks(
+    outgoing: &Outgoing,
+    transfer: &Transfer,
+    upload: &mut Upload,
+) -> Result<(), std::io::Error> {
+    let size = transfer.file.size;
+    upload
+        .streams
+        .retain(|_, stream| stream.retries <= MAX_FILE_RETRIES);
+    for (requester, stream) in upload.streams.iter_mut() {
+        if stream.last_ack.elapsed() > ACK_TIMEOUT {
+            stream.next = stream.acked;
+            stream.last_ack = Instant::now();
+            stream.retries += 1;
+        }
+        while stream.next < size && stream.next < stream.acked + FILE_WINDOW {
+            let data = transfer::read_chunk(&mut upload.file, stream.next)?;
+            if data.is_empty() {
+                return Err(std::io::Error::other("the file got shorter"));
+            }
+            let hash = transfer::chunk_hash(&data);
+            let len = data.len() as u64;
+            let data = match transfer.recipient {
+                Some(recipient) => outgoing
+                    .identity
+                    .direct_cipher(&recipient)
+                    .and_then(|cipher| cipher.seal(&file_aad(transfer.id, stream.next), &data).ok())
+                    .ok_or_else(|| std::io::Error::other("failed to encrypt the file"))?,
+                None => data,
+            };
+            let chunk = Op::FileChunk {
+                requester: *requester,
+                transfer: transfer.id,
+                offset: stream.next,
+                hash,
+                data,
+            };
+            if outgoing.send(&transfer.room.crypto, chunk).is_err() {
+                break;
+            }
+            stream.next += len;
+        }
+    }
+    Ok(())
+}
+
+fn lobby_receiver(
+    socket: Arc<UdpSocket>,
+    lobby: Lobby,
+    events: Sender<AppEvent>,
+    ttl: Duration,
+    shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    let mut read_buf: Vec<u8> = [0; 65536].to_vec();
+
+    while !shutdown.is_triggered() {
+        let amount_read = match socket.recv(&mut read_buf) {
+            Ok(v) => v,
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        let datagram = &read_buf[..amount_read];
+        let Ok((header, payload)) = protocol::unframe(datagram) else {
+            continue;
+        };
+        if header.room != LOBBY {
+            continue;
+        }
+        if let Some(announcement) = read_announcement(datagram, &header, payload, ttl) {
+            room::record(&lobby, announcement);
+            let _ = events.send(AppEvent::Network);
+        }
+    }
+    Ok(())
+}
+
+// Announcements are plain signed packets, stale or badly signed ones are
+// ignored.
+fn read_announcement(
+    datagram: &[u8],
+    header: &Header,
+    payload: &[u8],
+    ttl: Duration,
+) -> Option<Announcement> {
+    if header.flags != FLAG_SIGNED || header.opcode != OpCode::Announce {
+        return None;
+    }
+    let (body, key, signature) = protocol::split_signature(payload).ok()?;
+    let mut signed = datagram[..HEADER_LEN].to_vec();
+    signed.extend_from_slice(body);
+    if !identity::verify(&key, &signed, &signature) {
+        return None;
+    }
+    let packet = protocol::decode(header.opcode, body).ok()?;
+    if unix_millis_now().abs_diff(packet.timestamp) > MAX_CLOCK_SKEW.as_millis() as u64 {
+        return None;
+    }
+    let Op::Announce {
+        room,
+        label,
+        members,
+        passphrase,
+    } = packet.op
+    else {
+        return None;
+    };
+    Some(Announcement {
+        room,
+        label,
+        members,
+        passphrase,
+        expires: Instant::now() + ttl,
+    })
+}
+
+// Asks the sender for the numbers skipped before `number` straight away, the
+// heartbeat asks again until `MAX_NACKS` is reached.
+fn track_stream(
+    context: &ReceiverContext,
+    room: &Arc<Room>,
+    sender: u64,
+    number: u64,
+    received: bool,
+) {
+    let tag = room.crypto.room_tag();
+    let (ask, lost) = context
+        .inbound
+        .lock()
+        .unwrap()
+        .advance(tag, sender, number, received);
+    if !ask.is_empty() {
+        let nack = Op::Nack {
+            sender,
+            missing: ask,
+        };
+        let _ = context.outbound.send(Outbound::Op(room.clone(), nack));
+    }
+    if lost {
+        warn_missing(room, sender);
+    }
+}
+
+fn warn_missing(room: &Room, sender: u64) {
+    let username = room
+        .users
+        .lock()
+        .unwrap()
+        .get(&sender)
+        .map(|user| user.username.clone());
+    let text = match username {
+        Some(username) => format!(
+            "some messages from {} may be missing",
+            room.display_name(sender, &username)
+        ),
+        None => "some messages may be missing".to_string(),
+    };
+    room.chat.append(Arc::new(ChatMessage::system(text)));
+}
+
+// `since` is whatever the peer claims, so only a key the user verified can
+// make us give up our name.
+fn takes_name(
+    own: &OwnName,
+    own_instance: u64,
+    username: &str,
+    (since, instance): (u64, u64),
+    authenticity: &Authenticity,
+) -> bool {
+    matches!(authenticity, Authenticity::Signed(_, Trust::Verified))
+        && own.username == username
+        && (since, instance) < (own.since, own_instance)
+}
+
+// Our own name is never pinned to someone else's key, another instance
+// using it shows up as a changed key.
+// Moves our Lamport clock up to a received one and returns the clock to file
+// the message under. A clock far ahead of ours is cut down to
+// `MAX_CLOCK_JUMP` past it, so one bogus message can not use up the counter.
+fn observe_clock(clock: &AtomicU64, received: u64) -> u64 {
+    let bounded = |local: u64| received.min(local.saturating_add(MAX_CLOCK_JUMP));
+    let previous = clock
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |local| {
+            Some(local.max(bounded(local)))
+        })
+        .unwrap();
+    bounded(previous)
+}
+
+fn check_pinned_key(
+    known_peers: &Mutex<KnownPeers>,
+    room: &Room,
+    (own_name, own_key): (&str, &PublicKey),
+    username: &str,
+    key: &PublicKey,
+) -> Trust {
+    let mut known_peers = known_peers.lock().unwrap();
+    let trust = if username != own_name {
+        known_peers.observe(username, key)
+    } else if key == own_key {
+        Trust::Verified
+    } else {
+        Trust::Changed
+    };
+    if trust == Trust::Changed && known_peers.warn_once(username, key) {
+        drop(known_peers);
+        let warning = format!(
+            "warning: {} is using a new key {}, press <f> to compare fingerprints",
+            username,
+            identity::fingerprint(key)
+        );
+        room.chat.append(Arc::new(ChatMessage::system(warning)));
+    }
+    trust
+}
+
+fn presence_manager(
+    rooms: Rooms,
+    events: Sender<AppEvent>,
+    shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    loop {
+        let mut min_instant = Instant::now() + DEFAULT_HEARTBEAT;
+        let mut changed = false;
+        for room in rooms.lock().unwrap().iter() {
+            let mut presences_lock = room.presence.lock().unwrap();
+            let mut users_lock = room.users.lock().unwrap();
+            let mut to_del = Vec::new();
+            for (s, i) in &*presences_lock {
+                min_instant = min(min_instant, *i);
+                if *i < Instant::now() {
+                    to_del.push(*s);
+                }
+            }
+            changed |= !to_del.is_empty();
+            for s in to_del {
+                presences_lock.remove(&s);
+                users_lock.remove(&s);
+            }
+        }
+        if changed {
+            let _ = events.send(AppEvent::Network);
+        }
+        if shutdown.wait_timeout(min_instant - Instant::now()) {
+            return Ok(());
+        }
+    }
+}
+
+fn udp_sender(
+    outgoing: Outgoing,
+    rx: Receiver<Outbound>,
+    events: Sender<AppEvent>,
+) -> Result<(), std::io::Error> {
+    while let Ok(outbound) = rx.recv() {
+        match outbound {
+            Outbound::Op(room, op) => {
+                let _ = outgoing.send(&room.crypto, op);
+            }
+            Outbound::Message(room, message) => {
+                let op = outgoing.sent.lock().unwrap().record(
+                    room.crypto.room_tag(),
+                    outgoing.reliable,
+                    |number| Op::Message {
+                        id: message.id,
+                        number,
+                        clock: message.clock,
+                        timestamp: message.unix_millis(),
+                        author: message.author.clone(),
+                        text: message.text.clone(),
+                    },
+                );
+                match outgoing.send(&room.crypto, op) {
+                    Ok(()) => message.set_status(SendStatus::Sent),
+                    Err(_) => message.set_status(SendStatus::Failed),
+                }
+                let _ = events.send(AppEvent::Network);
+            }
+            Outbound::Resend(room, missing) => {
+                let tag = room.crypto.room_tag();
+                let ops: Vec<Op> = {
+                    let sent = outgoing.sent.lock().unwrap();
+                    missing
+                        .iter()
+                        .filter_map(|number| sent.find(tag, *number))
+                        .collect()
+                };
+                for op in ops {
+                    let _ = outgoing.send(&room.crypto, op);
+                }
+            }
+            Outbound::Direct(room, message, recipient) => {
+                let timestamp = message.unix_millis();
+                let sealed = outgoing
+                    .identity
+                    .direct_cipher(&recipient)
+                    .and_then(|cipher| {
+                        cipher
+                            .seal(&direct_aad(message.id, timestamp), message.text.as_bytes())
+                            .ok()
+                    });
+                let sent = match sealed {
+                    Some(sealed) => outgoing.send(
+                        &room.crypto,
+                        Op::Direct {
+                            id: message.id,
+                            clock: message.clock,
+                            timestamp,
+                            author: message.author.clone(),
+                            recipient,
+                            sealed,
+                        },
+                    ),
+                    None => Err(std::io::Error::other("failed to encrypt direct message")),
+                };
+                match sent {
+                    Ok(()) => message.set_status(SendStatus::Sent),
+                    Err(_) => message.set_status(SendStatus::Failed),
+                }
+                let _ = events.send(AppEvent::Network);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn heartbeat(
+    outgoing: Outgoing,
+    name: Arc<Mutex<OwnName>>,
+    inbound: Arc<Mutex<InboundStreams>>,
+    arcs: Arcs,
+    interval: Duration,
+    wake: Receiver<()>,
+    shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    while !shutdown.is_triggered() {
+        let presence = if arcs.activity.lock().unwrap().elapsed() > IDLE_AFTER {
+            Presence::Idle
+        } else {
+            Presence::Active
+        };
+        let (username, since) = {
+            let name = name.lock().unwrap();
+            (name.username.clone(), name.since)
+        };
+        let rooms = arcs.rooms.lock().unwrap().clone();
+        for room in rooms {
+            room.users.lock().unwrap().insert(
+                outgoing.instance,
+                OnlineUser {
+                    username: username.clone(),
+                    presence,
+                    authenticity: Authenticity::Signed(
+                        outgoing.identity.public_key(),
+                        Trust::Verified,
+                    ),
+                },
+            );
+            let _ = outgoing.send(&room.crypto, Op::User(username.clone(), since, presence));
+            let last = outgoing.sent.lock().unwrap().last(room.crypto.room_tag());
+            if outgoing.reliable && last > 0 {
+                let _ = outgoing.send(&room.crypto, Op::Latest { number: last });
+            }
+            if room.announce.load(Ordering::Relaxed) {
+                let members = room.users.lock().unwrap().len();
+                let _ = outgoing.announce(Op::Announce {
+                    room: room.crypto.room_tag(),
+                    label: room.name.clone(),
+                    members: members.min(u16::MAX as usize) as u16,
+                    passphrase: room.crypto.is_encrypted(),
+                });
+            }
+        }
+        let expired = expire_offers(&arcs.transfers);
+        for transfer in &expired {
+            transfer
+                .room
+                .chat
+                .append(Arc::new(ChatMessage::system(format!(
+                    "the offer of {} from {} expired",
+                    transfer.file.name, transfer.peer
+                ))));
+        }
+        if !expired.is_empty() {
+            let _ = arcs.events.send(AppEvent::Network);
+        }
+        let transfers = arcs.transfers.lock().unwrap().clone();
+        for transfer in transfers {
+            let Side::Upload(upload) = &transfer.side else {
+                continue;
+            };
+            if !matches!(
+                transfer.state(),
+                TransferState::Offered | TransferState::Active
+            ) || room::find(&arcs.rooms, &transfer.room.crypto.room_tag()).is_none()
+            {
+                continue;
+            }
+            let mut upload = upload.lock().unwrap();
+            if !upload.streams.is_empty() || upload.completed > 0 {
+                continue;
+            }
+            if upload.offered.elapsed() > OFFER_TTL {
+                transfer.set_state(TransferState::Done);
+                let _ = arcs.events.send(AppEvent::Network);
+            } else if upload.repeats < OFFER_REPEATS {
+                upload.repeats += 1;
+                let _ = outgoing.send(&transfer.room.crypto, file_offer(&transfer));
+            }
+        }
+        if outgoing.reliable {
+            let (nacks, lost) = inbound.lock().unwrap().retry(interval);
+            for ((tag, sender), missing) in nacks {
+                if let Some(room) = room::find(&arcs.rooms, &tag) {
+                    let _ = outgoing.send(&room.crypto, Op::Nack { sender, missing });
+                }
+            }
+            for (tag, sender) in &lost {
+                if let Some(room) = room::find(&arcs.rooms, tag) {
+                    warn_missing(&room, *sender);
+                }
+            }
+            if !lost.is_empty() {
+                let _ = arcs.events.send(AppEvent::Network);
+            }
+        }
+        let sent = Instant::now();
+        match wake.recv_timeout(interval) {
+            Ok(()) => {
+                while wake.try_recv().is_ok() {}
+                shutdown.wait_timeout(MIN_HEARTBEAT_GAP.saturating_sub(sent.elapsed()));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+impl Outgoing {
+    fn send(&self, crypto: &RoomCrypto, op: Op) -> Result<(), std::io::Error> {
+        let cipher = Some(crypto).filter(|crypto| crypto.is_encrypted());
+        self.send_to(crypto.room_tag(), cipher, op)
+    }
+
+    fn announce(&self, op: Op) -> Result<(), std::io::Error> {
+        self.send_to(LOBBY, None, op)
+    }
+
+    fn send_to(
+        &self,
+        room: RoomTag,
+        cipher: Option<&RoomCrypto>,
+        op: Op,
+    ) -> Result<(), std::io::Error> {
+        let opcode = op.opcode();
+        let packet = Packet {
+            instance: self.instance,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            timestamp: unix_millis_now(),
+            op,
+        };
+        let mut body = protocol::encode(&packet)
+            .map_err(|_| std::io::Error::other("a field is too long to send"))?;
+        let plaintext_len = body.len() + SIGNATURE_TRAILER_LEN;
+        let (flags, length) = if cipher.is_some() {
+            (
+                FLAG_ENCRYPTED | FLAG_SIGNED,
+                RoomCrypto::sealed_len(plaintext_len),
+            )
+        } else {
+            (FLAG_SIGNED, plaintext_len)
+        };
+        if length > u16::MAX as usize {
+            return Err(std::io::Error::other("packet too large"));
+        }
+        let header = Header::new(flags, opcode, room, length).to_bytes();
+        let mut signed = header.clone();
+        signed.extend_from_slice(&body);
+        let signature = self.identity.sign(&signed);
+        protocol::append_signature(&mut body, &self.identity.public_key(), &signature);
+        let payload = match cipher {
+            Some(crypto) => crypto
+                .seal(&header, &body)
+                .map_err(|_| std::io::Error::other("failed to encrypt packet"))?,
+            None => body,
+        };
+        let datagram = protocol::frame(flags, opcode, room, &payload);
+        let datagrams = if datagram.len() > MAX_DATAGRAM {
+            protocol::fragment(&datagram, self.instance, rand::random())
+                .into_iter()
+                .map(|piece| self.sign_piece(opcode, room, piece))
+                .collect()
+        } else {
+            vec![datagram]
+        };
+        let mut result = Ok(());
+        for datagram in datagrams {
+            let mut sent = Err(std::io::Error::other("no destination to send to"));
+            for link in &self.links {
+                for target in &link.targets {
+                    match link.socket.send_to(&datagram, target) {
+                        Ok(_) => sent = Ok(()),
+                        Err(err) if sent.is_err() => sent = Err(err),
+                        Err(_) => {}
+                    }
+                }
+            }
+            result = result.and(sent);
+        }
+        result
+    }
+
+    fn sign_piece(&self, opcode: OpCode, room: RoomTag, mut piece: Vec<u8>) -> Vec<u8> {
+        let flags = FLAG_FRAGMENT | FLAG_SIGNED;
+        let length = piece.len() + SIGNATURE_TRAILER_LEN;
+        let mut signed = Header::new(flags, opcode, room, length).to_bytes();
+        signed.extend_from_slice(&piece);
+        let signature = self.identity.sign(&signed);
+        protocol::append_signature(&mut piece, &self.identity.public_key(), &signature);
+        protocol::frame(flags, opcode, room, &piece)
+    }
+}
+
+impl Reassembly {
+    // Returns the whole datagram once its last piece is in. Pieces that do
+    // not fit what came before are ignored. A new datagram pushes out the
+    // oldest one of its sender, or of anyone once the buffer is full, so a
+    // flood only ever displaces itself or stale leftovers.
+    fn add(
+        &mut self,
+        room: RoomTag,
+        signer: Option<PublicKey>,
+        fragment: Fragment,
+    ) -> Option<Vec<u8>> {
+        self.pending
+            .retain(|_, partial| partial.started.elapsed() < REASSEMBLY_TIMEOUT);
+        let key = (room, fragment.instance, signer, fragment.id);
+        if !self.pending.contains_key(&key) {
+            let same_sender = |set: &PieceSet| (set.1, set.2) == (fragment.instance, signer);
+            let evict = if self.pending.keys().filter(|set| same_sender(set)).count()
+                >= MAX_REASSEMBLING_PER_SENDER
+            {
+                self.oldest(same_sender)
+            } else if self.pending.len() >= MAX_REASSEMBLING {
+                self.oldest(|_| true)
+            } else {
+                None
+            };
+            if let Some(evict) = evict {
+                self.pending.remove(&evict);
+            }
+        }
+        let count = fragment.count as usize;
+        let partial = self.pending.entry(key).or_insert_with(|| PartialDatagram {
+            pieces: vec![None; count],
+            missing: count,
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.pieces.len() != count || partial.pieces[fragment.index as usize].is_some() {
+            return None;
+        }
+        partial.size += fragment.data.len();
+        if partial.size > MAX_REASSEMBLED {
+            self.pending.remove(&key);
+            return None;
+        }
+        partial.pieces[fragment.index as usize] = Some(fragment.data.to_vec());
+        partial.missing -= 1;
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.pending.remove(&key)?;
+        Some(partial.pieces.into_iter().flatten().flatten().collect())
+    }
+
+    fn oldest(&self, filter: impl Fn(&PieceSet) -> bool) -> Option<PieceSet> {
+        self.pending
+            .iter()
+            .filter(|(set, _)| filter(set))
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(set, _)| *set)
+    }
+}
+
+impl RecentMessages {
+    fn insert(&mut self, instance: u64, id: u64) -> bool {
+        let now = Instant::now();
+        self.seen.retain(|_, expires| *expires > now);
+        self.seen
+            .insert((instance, id), now + DEDUP_WINDOW)
+            .is_none()
+    }
+}
+
+impl ResendBudget {
+    // How many of the `wanted` resends the requester still gets.
+    fn take(&mut self, requester: u64, wanted: usize) -> usize {
+        let now = Instant::now();
+        self.requesters
+            .retain(|_, (started, _)| now.duration_since(*started) < RESEND_WINDOW);
+        let (_, used) = self.requesters.entry(requester).or_insert((now, 0));
+        let granted = wanted.min(RETRANSMIT_BUFFER - *used);
+        *used += granted;
+        granted
+    }
+}
+
+impl HistoryReplies {
+    fn schedule(&mut self, room: &Arc<Room>, requester: u64, limit: u16) {
+        if self.pending.len() >= MAX_HISTORY_REPLIES {
+            return;
+        }
+        let delay = HISTORY_BACKOFF.mul_f64(rand::random::<f64>());
+        self.pending
+            .entry((room.crypto.room_tag(), requester))
+            .or_insert_with(|| PendingReply {
+                room: room.clone(),
+                limit,
+                due: Instant::now() + delay,
+            });
+    }
+
+    fn answered(&mut self, room: &Room, requester: u64) {
+        self.pending.remove(&(room.crypto.room_tag(), requester));
+    }
+
+    fn due(&mut self) -> Vec<(u64, PendingReply)> {
+        let now = Instant::now();
+        let due: Vec<(RoomTag, u64)> = self
+            .pending
+            .iter()
+            .filter(|(_, reply)| reply.due <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        due.into_iter()
+            .filter_map(|key| Some((key.1, self.pending.remove(&key)?)))
+            .collect()
+    }
+}
+
+impl ReplayWindows {
+    fn accept(&mut self, instance: u64, key: Option<PublicKey>, sequence: u64) -> SequenceCheck {
+        let now = Instant::now();
+        self.senders
+            .retain(|_, window| now - window.last_packet < MAX_CLOCK_SKEW * 2);
+        let window = match self.senders.get_mut(&(instance, key)) {
+            Some(v) => v,
+            None => {
+                self.senders.insert(
+                    (instance, key),
+                    SenderWindow {
+                        highest: sequence,
+                        seen: 1,
+                        last_packet: now,
+                    },
+                );
+                return SequenceCheck::Fresh;
+            }
+        };
+        if sequence > window.highest {
+            let shift = sequence - window.highest;
+            window.seen = if shift < REPLAY_WINDOW {
+                (window.seen << shift) | 1
+            } else {
+                1
+            };
+            window.highest = sequence;
+        } else {
+            let age = window.highest - sequence;
+            if age >= REPLAY_WINDOW {
+                return SequenceCheck::TooOld;
+            }
+            if window.seen & (1 << age) != 0 {
+                return SequenceCheck::Repeated;
+            }
+            window.seen |= 1 << age;
+        }
+        window.last_packet = now;
+        SequenceCheck::Fresh
+    }
+}
+
+impl SentMessages {
+    fn record(&mut self, room: RoomTag, keep: bool, op: impl FnOnce(u64) -> Op) -> Op {
+        let stream = self.rooms.entry(room).or_default();
+        stream.last += 1;
+        let op = op(stream.last);
+        if keep {
+            if stream.buffer.len() == RETRANSMIT_BUFFER {
+                stream.buffer.pop_front();
+            }
+            stream.buffer.push_back(op.clone());
+        }
+        op
+    }
+
+    fn last(&self, room: RoomTag) -> u64 {
+        self.rooms.get(&room).map_or(0, |stream| stream.last)
+    }
+
+    fn find(&self, room: RoomTag, number: u64) -> Option<Op> {
+        self.rooms
+            .get(&room)?
+            .buffer
+            .iter()
+            .find(|op| matches!(op, Op::Message { number: n, .. } if *n == number))
+            .cloned()
+    }
+}
+
+impl InboundStreams {
+    // `number` is a message that arrived when `received`, otherwise the last
+    // one the sender says it sent. Returns the newly skipped numbers and
+    // whether some were too far back to ask for.
+    fn advance(
+        &mut self,
+        room: RoomTag,
+        sender: u64,
+        number: u64,
+        received: bool,
+    ) -> (Vec<u64>, bool) {
+        let now = Instant::now();
+        self.streams
+            .retain(|_, stream| now - stream.last_packet < MAX_CLOCK_SKEW * 2);
+        let stream = match self.streams.get_mut(&(room, sender)) {
+            Some(v) => v,
+            None => {
+                self.streams.insert(
+                    (room, sender),
+                    InboundStream {
+                        next: number.saturating_add(1),
+                        missing: BTreeMap::new(),
+                        reported: 0,
+                        last_packet: now,
+                    },
+                );
+                return (Vec::new(), false);
+            }
+        };
+        stream.last_packet = now;
+        let end = if received {
+            number
+        } else {
+            number.saturating_add(1)
+        };
+        if end <= stream.next {
+            if received {
+                stream.missing.remove(&number);
+                stream.next = stream.next.max(number.saturating_add(1));
+            }
+            return (Vec::new(), false);
+        }
+        let gap = stream.next;
+        let start = gap.max(end.saturating_sub(RETRANSMIT_BUFFER as u64));
+        let lost = start > gap;
+        if lost {
+            stream.reported = gap + 1;
+        }
+        for missing in start..end {
+            stream.missing.insert(
+                missing,
+                Missing {
+                    tries: 1,
+                    asked: now,
+                    gap,
+                },
+            );
+        }
+        stream.next = number.saturating_add(1);
+        ((start..end).collect(), lost)
+    }
+
+    fn retry(&mut self, interval: Duration) -> (Vec<(StreamId, Vec<u64>)>, Vec<StreamId>) {
+        let now = Instant::now();
+        let mut nacks = Vec::new();
+        let mut lost = Vec::new();
+        for (id, stream) in &mut self.streams {
+            let mut ask = Vec::new();
+            let mut given_up = None;
+            stream.missing.retain(|number, missing| {
+                if now - missing.asked < interval {
+                    return true;
+                }
+                if missing.tries >= MAX_NACKS {
+                    given_up = given_up.max(Some(missing.gap));
+                    return false;
+                }
+                missing.tries += 1;
+                missing.asked = now;
+                ask.push(*number);
+                true
+            });
+            if let Some(gap) = given_up.filter(|gap| *gap >= stream.reported) {
+                stream.reported = gap + 1;
+                lost.push(*id);
+            }
+            if !ask.is_empty() {
+                nacks.push((*id, ask));
+            }
+        }
+        (nacks, lost)
+    }
+}
+
+fn direct_aad(id: u64, timestamp: u64) -> Vec<u8> {
+    [id.to_be_bytes(), timestamp.to_be_bytes()].concat()
+}
+
+fn file_aad(transfer: u64, offset: u64) -> Vec<u8> {
+    [transfer.to_be_bytes(), offset.to_be_bytes()].concat()
+}
+
+fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::PUBLIC_KEY_LEN;
+
+    const ALICE: PublicKey = [0xa1; PUBLIC_KEY_LEN];
+    const BOB: PublicKey = [0xb0; PUBLIC_KEY_LEN];
+
+    fn room() -> Arc<Room> {
+        Arc::new(Room::new(
+            "room".into(),
+            RoomCrypto::derive("room", None).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn replay_window_in_order() {
+        let mut replay = ReplayWindows::default();
+        for sequence in 1..200 {
+            assert_eq!(
+                replay.accept(7, Some(ALICE), sequence),
+                SequenceCheck::Fresh
+            );
+        }
+        assert_eq!(replay.accept(7, Some(ALICE), 199), SequenceCheck::Repeated);
+    }
+
+    #[test]
+    fn replay_window_out_of_order() {
+        let mut replay = ReplayWindows::default();
+        assert_eq!(replay.accept(7, Some(ALICE), 100), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 98), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 101), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 99), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 98), SequenceCheck::Repeated);
+        assert_eq!(replay.accept(7, Some(ALICE), 100), SequenceCheck::Repeated);
+        // The oldest number still inside the window.
+        assert_eq!(
+            replay.accept(7, Some(ALICE), 101 - (REPLAY_WINDOW - 1)),
+            SequenceCheck::Fresh
+        );
+    }
+
+    #[test]
+    fn replay_window_too_old() {
+        let mut replay = ReplayWindows::default();
+        assert_eq!(replay.accept(7, Some(ALICE), 100), SequenceCheck::Fresh);
+        assert_eq!(
+            replay.accept(7, Some(ALICE), 100 - REPLAY_WINDOW),
+            SequenceCheck::TooOld
+        );
+        assert_eq!(replay.accept(7, Some(ALICE), 0), SequenceCheck::TooOld);
+    }
+
+    #[test]
+    fn replay_window_big_jump() {
+        let mut replay = ReplayWindows::default();
+        assert_eq!(replay.accept(7, Some(ALICE), 5), SequenceCheck::Fresh);
+        assert_eq!(
+            replay.accept(7, Some(ALICE), 5 + 1000),
+            SequenceCheck::Fresh
+        );
+        assert_eq!(replay.accept(7, Some(ALICE), 5), SequenceCheck::TooOld);
+        // Nothing below the jump was seen, inside the window it is still new.
+        assert_eq!(replay.accept(7, Some(ALICE), 1004), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 1005), SequenceCheck::Repeated);
+    }
+
+    #[test]
+    fn replay_window_per_sender() {
+        let mut replay = ReplayWindows::default();
+        assert_eq!(replay.accept(7, Some(ALICE), 10), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(8, Some(ALICE), 10), SequenceCheck::Fresh);
+        // Someone else claiming the same instance can not move its window.
+        assert_eq!(
+            replay.accept(7, Some(BOB), u64::MAX - 1),
+            SequenceCheck::Fresh
+        );
+        assert_eq!(replay.accept(7, None, u64::MAX - 1), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, Some(ALICE), 11), SequenceCheck::Fresh);
+        assert_eq!(replay.accept(7, None, 11), SequenceCheck::TooOld);
+    }
+
+    const ROOM: RoomTag = *b"roomtag!";
+
+    #[test]
+    fn inbound_streams_advance() {
+        let mut inbound = InboundStreams::default();
+        assert_eq!(inbound.advance(ROOM, 7, 5, true), (vec![], false));
+        assert_eq!(inbound.advance(ROOM, 7, 6, true), (vec![], false));
+        assert_eq!(inbound.advance(ROOM, 7, 9, true), (vec![7, 8], false));
+        assert_eq!(inbound.advance(ROOM, 7, 8, true), (vec![], false));
+        assert_eq!(inbound.advance(ROOM, 7, 8, true), (vec![], false));
+        // A heartbeat reporting the last message sent.
+        assert_eq!(inbound.advance(ROOM, 7, 11, false), (vec![10, 11], false));
+        assert_eq!(inbound.advance(ROOM, 7, 11, false), (vec![], false));
+        // Another sender, or the same one in another room, starts fresh.
+        assert_eq!(inbound.advance(ROOM, 8, 100, true), (vec![], false));
+        assert_eq!(inbound.advance(*b"otherrm!", 7, 100, true), (vec![], false));
+        let missing = &inbound.streams[&(ROOM, 7)].missing;
+        assert_eq!(missing.keys().copied().collect::<Vec<_>>(), vec![7, 10, 11]);
+    }
+
+    #[test]
+    fn inbound_streams_gap_beyond_buffer() {
+        let mut inbound = InboundStreams::default();
+        inbound.advance(ROOM, 7, 1, true);
+        let jump = 2 + RETRANSMIT_BUFFER as u64 + 10;
+        let (ask, lost) = inbound.advance(ROOM, 7, jump, true);
+        assert!(lost);
+        assert_eq!(
+            ask,
+            (jump - RETRANSMIT_BUFFER as u64..jump).collect::<Vec<_>>()
+        );
+        // Giving up on the rest of the same gap is not reported again.
+        for _ in 0..MAX_NACKS {
+            assert!(inbound.retry(Duration::ZERO).1.is_empty());
+        }
+        assert!(inbound.streams[&(ROOM, 7)].missing.is_empty());
+    }
+
+    #[test]
+    fn inbound_streams_retry() {
+        let mut inbound = InboundStreams::default();
+        inbound.advance(ROOM, 7, 1, true);
+        inbound.advance(ROOM, 7, 4, true);
+        // Not due yet.
+        assert_eq!(inbound.retry(Duration::from_secs(60)), (vec![], vec![]));
+        for _ in 1..MAX_NACKS {
+            assert_eq!(
+                inbound.retry(Duration::ZERO),
+                (vec![((ROOM, 7), vec![2, 3])], vec![])
+            );
+        }
+        // Filled in by a retransmission.
+        inbound.advance(ROOM, 7, 2, true);
+        assert_eq!(inbound.retry(Duration::ZERO), (vec![], vec![(ROOM, 7)]));
+        assert_eq!(inbound.retry(Duration::ZERO), (vec![], vec![]));
+
+        // A later gap gets its own notice, once.
+        inbound.advance(ROOM, 7, 6, true);
+        for _ in 1..MAX_NACKS {
+            assert_eq!(inbound.retry(Duration::ZERO).1, vec![]);
+        }
+        assert_eq!(inbound.retry(Duration::ZERO).1, vec![(ROOM, 7)]);
+        assert_eq!(inbound.retry(Duration::ZERO), (vec![], vec![]));
+    }
+
+    fn piece(instance: u64, index: u16, count: u16, data: &[u8]) -> Fragment<'_> {
+        Fragment {
+            instance,
+            id: 0xf00d,
+            index,
+            count,
+            data,
+        }
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 2, 3, b"c")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 3, b"a")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 1, 3, b"b")),
+            Some(b"abc".to_vec())
+        );
+        assert!(reassembly.pending.is_empty());
+    }
+
+    #[test]
+    fn reassembly_ignores_duplicates_and_bad_counts() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 2, b"a")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 2, b"x")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 1, 3, b"x")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 1, 2, b"b")),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn reassembly_keeps_senders_apart() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 2, b"a")),
+            None
+        );
+        // Same id, but another key, no key or another instance.
+        assert_eq!(reassembly.add(ROOM, Some(BOB), piece(7, 1, 2, b"x")), None);
+        assert_eq!(reassembly.add(ROOM, None, piece(7, 1, 2, b"x")), None);
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(8, 1, 2, b"x")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(*b"otherrm!", Some(ALICE), piece(7, 1, 2, b"x")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 1, 2, b"b")),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn reassembly_times_out() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 2, b"a")),
+            None
+        );
+        for partial in reassembly.pending.values_mut() {
+            partial.started = Instant::now() - REASSEMBLY_TIMEOUT;
+        }
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 1, 2, b"b")),
+            None
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(ALICE), piece(7, 0, 2, b"a")),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let mut reassembly = Reassembly::default();
+        let data = vec![0; protocol::FRAGMENT_DATA_LEN];
+        let count = protocol::MAX_FRAGMENTS as u16;
+        // A full set of full pieces is longer than any datagram can be.
+        for index in 0..count {
+            assert_eq!(
+                reassembly.add(ROOM, Some(ALICE), piece(7, index, count, &data)),
+                None
+            );
+        }
+        assert!(reassembly.pending.is_empty());
+        let last = protocol::MAX_REASSEMBLED - (count as usize - 1) * data.len();
+        for index in 0..count - 1 {
+            assert_eq!(
+                reassembly.add(ROOM, Some(ALICE), piece(7, index, count, &data)),
+                None
+            );
+        }
+        let whole = reassembly.add(ROOM, Some(ALICE), piece(7, count - 1, count, &data[..last]));
+        assert_eq!(
+            whole.map(|whole| whole.len()),
+            Some(protocol::MAX_REASSEMBLED)
+        );
+
+        for instance in 0..MAX_REASSEMBLING as u64 {
+            reassembly.add(ROOM, Some(ALICE), piece(instance, 0, 2, b"a"));
+        }
+        // A full buffer makes room for a newcomer.
+        assert_eq!(reassembly.add(ROOM, Some(BOB), piece(7, 0, 2, b"a")), None);
+        assert_eq!(reassembly.pending.len(), MAX_REASSEMBLING);
+        assert_eq!(
+            reassembly.add(ROOM, Some(BOB), piece(7, 1, 2, b"b")),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn reassembly_flood_does_not_block_others() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(reassembly.add(ROOM, Some(BOB), piece(8, 0, 2, b"a")), None);
+        for id in 0..1000 {
+            let flood = Fragment {
+                id,
+                ..piece(7, 0, 2, b"x")
+            };
+            assert_eq!(reassembly.add(ROOM, Some(ALICE), flood), None);
+            assert_eq!(reassembly.add(ROOM, None, flood), None);
+        }
+        assert_eq!(
+            reassembly.pending.len(),
+            2 * MAX_REASSEMBLING_PER_SENDER + 1
+        );
+        assert_eq!(
+            reassembly.add(ROOM, Some(BOB), piece(8, 1, 2, b"b")),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn unaccepted_offers_expire() {
+        let room = room();
+        let offer = |age: Duration, state: TransferState| {
+            let mut download = Download::new(ALICE);
+            download.offered = Instant::now().checked_sub(age).unwrap();
+            let transfer = Arc::new(Transfer::new(
+                rand::random(),
+                7,
+                None,
+                room.clone(),
+                FileInfo {
+                    name: "file".into(),
+                    size: 1,
+                    hash: [0; protocol::HASH_LEN],
+                },
+                "alice".into(),
+                Side::Download(Mutex::new(download)),
+            ));
+            transfer.set_state(state);
+            transfer
+        };
+        let stale = OFFER_TTL + Duration::from_secs(1);
+        let fresh = offer(Duration::ZERO, TransferState::Offered);
+        let expired = offer(stale, TransferState::Offered);
+        let accepted = offer(stale, TransferState::Active);
+        let transfers: Transfers = Arc::new(Mutex::new(vec![
+            fresh.clone(),
+            expired.clone(),
+            accepted.clone(),
+        ]));
+
+        let gone = expire_offers(&transfers);
+        assert_eq!(gone.len(), 1);
+        assert!(Arc::ptr_eq(&gone[0], &expired));
+        let left: Vec<usize> = transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|transfer| transfer.number)
+            .collect();
+        assert_eq!(left, vec![fresh.number, accepted.number]);
+        assert!(expire_offers(&transfers).is_empty());
+    }
+
+    #[test]
+    fn new_keys_can_not_take_our_name() {
+        let own = OwnName {
+            username: "alice".into(),
+            since: 1000,
+        };
+        let claim = |since, authenticity| takes_name(&own, 7, "alice", (since, 8), &authenticity);
+        assert!(!claim(0, Authenticity::Signed(BOB, Trust::Pinned)));
+        assert!(!claim(0, Authenticity::Signed(BOB, Trust::Changed)));
+        assert!(!claim(0, Authenticity::Unsigned));
+        assert!(!claim(2000, Authenticity::Signed(BOB, Trust::Verified)));
+        assert!(claim(0, Authenticity::Signed(BOB, Trust::Verified)));
+        assert!(!takes_name(
+            &own,
+            7,
+            "bob",
+            (0, 8),
+            &Authenticity::Signed(BOB, Trust::Verified)
+        ));
+    }
+
+    #[test]
+    fn own_name_is_not_pinned() {
+        let room = room();
+        let known_peers = Mutex::new(KnownPeers::default());
+        let own = ("alice", &ALICE);
+        assert_eq!(
+            check_pinned_key(&known_peers, &room, own, "alice", &BOB),
+            Trust::Changed
+        );
+        assert!(known_peers.lock().unwrap().get("alice").is_none());
+        assert_eq!(
+            check_pinned_key(&known_peers, &room, own, "alice", &ALICE),
+            Trust::Verified
+        );
+        assert_eq!(
+            check_pinned_key(&known_peers, &room, own, "bob", &BOB),
+            Trust::Pinned
+        );
+        assert!(known_peers.lock().unwrap().get("bob").is_some());
+        assert_eq!(
+            check_pinned_key(&known_peers, &room, own, "bob", &ALICE),
+            Trust::Changed
+        );
+    }
+
+    #[test]
+    fn received_clocks_are_bounded() {
+        let clock = AtomicU64::new(10);
+        assert_eq!(observe_clock(&clock, 5), 5);
+        assert_eq!(clock.load(Ordering::Relaxed), 10);
+        assert_eq!(observe_clock(&clock, 20), 20);
+        assert_eq!(clock.load(Ordering::Relaxed), 20);
+        assert_eq!(observe_clock(&clock, u64::MAX), 20 + MAX_CLOCK_JUMP);
+        assert_eq!(clock.load(Ordering::Relaxed), 20 + MAX_CLOCK_JUMP);
+    }
+
+    #[test]
+    fn resends_are_budgeted_per_requester() {
+        let mut resends = ResendBudget::default();
+        assert_eq!(resends.take(7, 200), 200);
+        assert_eq!(resends.take(7, 200), RETRANSMIT_BUFFER - 200);
+        assert_eq!(resends.take(7, 1), 0);
+        assert_eq!(resends.take(8, 1), 1);
+        resends.requesters.get_mut(&7).unwrap().0 -= RESEND_WINDOW;
+        assert_eq!(resends.take(7, 1), 1);
     }
 }
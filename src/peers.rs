@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::identity::{PublicKey, PUBLIC_KEY_LEN};
+
+// One line per peer: `<key as hex> <verified|pinned> <username>`.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trust {
+    Pinned,
+    Verified,
+    Changed,
+}
+
+pub struct KnownPeer {
+    pub key: PublicKey,
+    pub verified: bool,
+}
+
+#[derive(Default)]
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    peers: HashMap<String, KnownPeer>,
+    warned: HashSet<(String, PublicKey)>,
+    // Keys pinned since the last save, written out by the app thread.
+    unsaved: bool,
+}
+
+impl KnownPeers {
+    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
+        let mut known = KnownPeers {
+            path: Some(path.to_path_buf()),
+            ..KnownPeers::default()
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(known),
+            Err(err) => return Err(err),
+        };
+        for (n, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line =
+                || std::io::Error::other(format!("{}:{}: malformed line", path.display(), n + 1));
+            let mut fields = line.splitn(3, ' ');
+            let key = fields.next().and_then(parse_key).ok_or_else(bad_line)?;
+            let verified = match fields.next() {
+                Some("verified") => true,
+                Some("pinned") => false,
+                _ => return Err(bad_line()),
+            };
+            let username = fields.next().ok_or_else(bad_line)?;
+            known
+                .peers
+                .insert(username.to_string(), KnownPeer { key, verified });
+        }
+        Ok(known)
+    }
+
+    pub fn get(&self, username: &str) -> Option<&KnownPeer> {
+        self.peers.get(username)
+    }
+
+    // Pins the key the first time a name is seen, afterwards only reports
+    // whether the key still matches.
+    pub fn observe(&mut self, username: &str, key: &PublicKey) -> Trust {
+        match self.peers.get(username) {
+            Some(peer) if peer.key != *key => Trust::Changed,
+            Some(peer) if peer.verified => Trust::Verified,
+            Some(_) => Trust::Pinned,
+            None => {
+                if pinnable(username) {
+                    self.peers.insert(
+                        username.to_string(),
+                        KnownPeer {
+                            key: *key,
+                            verified: false,
+                        },
+                    );
+                    self.unsaved = true;
+                }
+                Trust::Pinned
+            }
+        }
+    }
+
+    pub fn warn_once(&mut self, username: &str, key: &PublicKey) -> bool {
+        self.warned.insert((username.to_string(), *key))
+    }
+
+    pub fn set_verified(
+        &mut self,
+        username: &str,
+        key: &PublicKey,
+        verified: bool,
+    ) -> Result<(), std::io::Error> {
+        if !pinnable(username) {
+            return Err(std::io::Error::other("username can not be stored"));
+        }
+        self.peers.insert(
+            username.to_string(),
+            KnownPeer {
+                key: *key,
+                verified,
+            },
+        );
+        self.save()
+    }
+
+    // Takes what needs writing so the file can be saved without holding
+    // the lock the receive thread needs.
+    pub fn take_unsaved(&mut self) -> Option<(PathBuf, String)> {
+        if !std::mem::take(&mut self.unsaved) {
+            return None;
+        }
+        Some((self.path.clone()?, self.contents()))
+    }
+
+    fn save(&mut self) -> Result<(), std::io::Error> {
+        self.unsaved = false;
+        match &self.path {
+            Some(path) => write(path, &self.contents()),
+            None => Ok(()),
+        }
+    }
+
+    fn contents(&self) -> String {
+        let mut names: Vec<&String> = self.peers.keys().collect();
+        names.sort();
+        let mut contents = String::new();
+        for name in names {
+            let peer = &self.peers[name];
+            let trust = if peer.verified { "verified" } else { "pinned" };
+            contents += &format!("{} {} {}\n", to_hex(&peer.key), trust, name);
+        }
+        contents
+    }
+}
+
+pub fn write(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("hackchat").join("known_peers"))
+}
+
+fn pinnable(username: &str) -> bool {
+    !username.is_empty() && !username.contains(['\n', '\r'])
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_key(hex: &str) -> Option<PublicKey> {
+    if hex.len() != PUBLIC_KEY_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; PUBLIC_KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: PublicKey = [0xa1; PUBLIC_KEY_LEN];
+    const BOB: PublicKey = [0xb0; PUBLIC_KEY_LEN];
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hackchat-peers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn load_parses_lines() {
+        let path = temp_path("parse");
+        let contents = format!(
+            "{} verified alice\n\n{} pinned bob the builder\n",
+            to_hex(&ALICE),
+            to_hex(&BOB)
+        );
+        fs::write(&path, contents).unwrap();
+        let known = KnownPeers::load(&path).unwrap();
+        let alice = known.get("alice").unwrap();
+        assert_eq!((alice.key, alice.verified), (ALICE, true));
+        let bob = known.get("bob the builder").unwrap();
+        assert_eq!((bob.key, bob.verified), (BOB, false));
+        assert!(known.get("bob").is_none());
+    }
+
+    #[test]
+    fn load_missing_file_is_empty() {
+        let known = KnownPeers::load(&temp_path("missing")).unwrap();
+        assert!(known.get("alice").is_none());
+    }
+
+    #[test]
+    fn load_rejects_malformed_lines() {
+        let path = temp_path("malformed");
+        for contents in [
+            "zz verified alice".to_string(),
+            format!("{}00 pinned alice", to_hex(&ALICE)),
+            format!("{} trusted alice", to_hex(&ALICE)),
+            format!("{} pinned", to_hex(&ALICE)),
+            to_hex(&ALICE),
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert!(KnownPeers::load(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let path = temp_path("round-trip");
+        let _ = fs::remove_file(&path);
+        let mut known = KnownPeers::load(&path).unwrap();
+        assert_eq!(known.observe("alice", &ALICE), Trust::Pinned);
+        assert_eq!(known.observe("alice", &BOB), Trust::Changed);
+        assert_eq!(known.observe("line\nbreak", &BOB), Trust::Pinned);
+        let (saved, contents) = known.take_unsaved().unwrap();
+        assert_eq!(saved, path);
+        assert!(known.take_unsaved().is_none());
+        write(&saved, &contents).unwrap();
+        known.set_verified("bob", &BOB, true).unwrap();
+        assert!(known.take_unsaved().is_none());
+
+        let loaded = KnownPeers::load(&path).unwrap();
+        let alice = loaded.get("alice").unwrap();
+        assert_eq!((alice.key, alice.verified), (ALICE, false));
+        let bob = loaded.get("bob").unwrap();
+        assert_eq!((bob.key, bob.verified), (BOB, true));
+        assert!(loaded.get("line\nbreak").is_none());
+    }
+}
@@ -0,0 +1,1064 @@
+// Every datagram is a fixed header followed by `length` bytes of payload:
+//
+//   0      4        5      6       7      15       17
+//   +------+--------+------+-------+------+--------+---------+
+//   | magic| version| flags| opcode| room | length | payload |
+//   +------+--------+------+-------+------+--------+---------+
+//
+// `room` is the tag derived together with the room key, so packets for
+// other rooms can be dropped without trying to decrypt them. `length` is
+// big endian. With `FLAG_ENCRYPTED` set the payload is the
+// body sealed with the room key, using the header as associated data,
+// otherwise it is the body itself. Bodies are built by
+// `encode` and read back by `decode`. A body starts with the sender's
+// instance id, its packet sequence number and the send time in unix
+// milliseconds (all u64), followed by the fields of the op. Integers are big
+// endian and strings are prefixed with their length as a u16.
+//
+// Messages carry a Lamport clock: every peer keeps the highest clock it has
+// seen and stamps its own messages one above it, so a reply always sorts
+// after the message it answers.
+//
+// With `FLAG_SIGNED` set the body is followed by the sender's Ed25519
+// public key and a signature over the header and the body, all of it
+// inside the encrypted payload.
+//
+// Datagrams longer than `MAX_DATAGRAM` are cut into pieces that each fit
+// the IPv6 minimum MTU. A piece has `FLAG_FRAGMENT` set and the original
+// opcode and room, and its payload is the sender's instance id, a random id
+// shared by all pieces of the datagram, the piece's index and the piece
+// count (u64, u64, u16, u16), followed by the next slice of the whole
+// datagram. Pieces are signed like whole packets, but never encrypted, so
+// the receiver only puts together pieces from the same key. It then reads
+// the datagram as if it came in one piece.
+//
+// Room announcements travel under the all-zero `LOBBY` tag and are never
+// encrypted, so they can be read before joining anything. They carry the
+// room's public label and tag, its member count and whether it needs a
+// passphrase, never the key.
+
+use crate::crypto::{RoomTag, ROOM_TAG_LEN};
+use crate::identity::{PublicKey, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+
+pub const MAGIC: [u8; 4] = *b"HKCH";
+pub const VERSION: u8 = 5;
+pub const HEADER_LEN: usize = 9 + ROOM_TAG_LEN;
+
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+pub const FLAG_SIGNED: u8 = 0b0000_0010;
+pub const FLAG_FRAGMENT: u8 = 0b0000_0100;
+pub const SIGNATURE_TRAILER_LEN: usize = PUBLIC_KEY_LEN + SIGNATURE_LEN;
+pub const LOBBY: RoomTag = [0; ROOM_TAG_LEN];
+pub const MAX_DATAGRAM: usize = 1200;
+pub const FRAGMENT_HEADER_LEN: usize = 20;
+pub const FRAGMENT_DATA_LEN: usize =
+    MAX_DATAGRAM - HEADER_LEN - FRAGMENT_HEADER_LEN - SIGNATURE_TRAILER_LEN;
+pub const MAX_REASSEMBLED: usize = HEADER_LEN + u16::MAX as usize;
+pub const MAX_FRAGMENTS: usize = MAX_REASSEMBLED.div_ceil(FRAGMENT_DATA_LEN);
+// Longest message text in bytes, longer ones do not decode.
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024;
+pub const HASH_LEN: usize = 32;
+
+pub type FileHash = [u8; HASH_LEN];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub instance: u64,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Message {
+        id: u64,
+        // Counts the sender's messages in this room from 1, gaps show loss.
+        number: u64,
+        clock: u64,
+        timestamp: u64,
+        author: String,
+        text: String,
+    },
+    // Username, when the sender took that name in unix milliseconds, presence.
+    User(String, u64, Presence),
+    Leave(String),
+    Direct {
+        id: u64,
+        clock: u64,
+        timestamp: u64,
+        author: String,
+        recipient: PublicKey,
+        sealed: Vec<u8>,
+    },
+    Announce {
+        room: RoomTag,
+        label: String,
+        members: u16,
+        passphrase: bool,
+    },
+    Nack {
+        sender: u64,
+        missing: Vec<u64>,
+    },
+    Latest {
+        number: u64,
+    },
+    HistoryRequest {
+        limit: u16,
+    },
+    // One chunk of a reply to `requester`'s `HistoryRequest`, oldest first.
+    History {
+        requester: u64,
+        entries: Vec<HistoryEntry>,
+    },
+    // A file for the room, or only for `recipient` when set.
+    FileOffer {
+        transfer: u64,
+        name: String,
+        size: u64,
+        hash: FileHash,
+        recipient: Option<PublicKey>,
+    },
+    // Everything before `offset` arrived. With `resend` the chunks after it
+    // are sent (again), this is also how an offer is accepted or resumed.
+    FileAck {
+        sender: u64,
+        transfer: u64,
+        offset: u64,
+        resend: bool,
+    },
+    FileChunk {
+        requester: u64,
+        transfer: u64,
+        offset: u64,
+        // Of the data before it was sealed to the recipient.
+        hash: FileHash,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    // Instance id of whoever first sent the message.
+    pub origin: u64,
+    pub id: u64,
+    pub clock: u64,
+    pub timestamp: u64,
+    pub author: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Active = 0,
+    Idle = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Message = 0,
+    User = 1,
+    Leave = 2,
+    Direct = 3,
+    Announce = 4,
+    Nack = 5,
+    Latest = 6,
+    HistoryRequest = 7,
+    History = 8,
+    FileOffer = 9,
+    FileAck = 10,
+    FileChunk = 11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment<'a> {
+    pub instance: u64,
+    pub id: u64,
+    pub index: u16,
+    pub count: u16,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    pub opcode: OpCode,
+    pub room: RoomTag,
+    pub length: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    // A string or byte field does not fit its u16 length prefix.
+    FieldTooLong,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownOpCode(u8),
+    UnknownPresence(u8),
+    LengthMismatch,
+    InvalidUtf8,
+    TrailingBytes,
+    BadFragment,
+    MessageTooLong,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Message),
+            1 => Ok(Self::User),
+            2 => Ok(Self::Leave),
+            3 => Ok(Self::Direct),
+            4 => Ok(Self::Announce),
+            5 => Ok(Self::Nack),
+            6 => Ok(Self::Latest),
+            7 => Ok(Self::HistoryRequest),
+            8 => Ok(Self::History),
+            9 => Ok(Self::FileOffer),
+            10 => Ok(Self::FileAck),
+            11 => Ok(Self::FileChunk),
+            v => Err(DecodeError::UnknownOpCode(v)),
+        }
+    }
+}
+
+impl TryFrom<u8> for Presence {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Active),
+            1 => Ok(Self::Idle),
+            v => Err(DecodeError::UnknownPresence(v)),
+        }
+    }
+}
+
+impl Op {
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Op::Message { .. } => OpCode::Message,
+            Op::User(..) => OpCode::User,
+            Op::Leave(..) => OpCode::Leave,
+            Op::Direct { .. } => OpCode::Direct,
+            Op::Announce { .. } => OpCode::Announce,
+            Op::Nack { .. } => OpCode::Nack,
+            Op::Latest { .. } => OpCode::Latest,
+            Op::HistoryRequest { .. } => OpCode::HistoryRequest,
+            Op::History { .. } => OpCode::History,
+            Op::FileOffer { .. } => OpCode::FileOffer,
+            Op::FileAck { .. } => OpCode::FileAck,
+            Op::FileChunk { .. } => OpCode::FileChunk,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        match self {
+            Op::Message { author, .. } => author,
+            Op::User(username, ..) => username,
+            Op::Leave(username) => username,
+            Op::Direct { author, .. } => author,
+            Op::Announce { .. }
+            | Op::Nack { .. }
+            | Op::Latest { .. }
+            | Op::HistoryRequest { .. }
+            | Op::History { .. }
+            | Op::FileOffer { .. }
+            | Op::FileAck { .. }
+            | Op::FileChunk { .. } => "",
+        }
+    }
+}
+
+pub fn encode(packet: &Packet) -> Result<Vec<u8>, EncodeError> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet.instance.to_be_bytes());
+    body.extend_from_slice(&packet.sequence.to_be_bytes());
+    body.extend_from_slice(&packet.timestamp.to_be_bytes());
+    match &packet.op {
+        Op::Message {
+            id,
+            number,
+            clock,
+            timestamp,
+            author,
+            text,
+        } => {
+            body.extend_from_slice(&id.to_be_bytes());
+            body.extend_from_slice(&number.to_be_bytes());
+            body.extend_from_slice(&clock.to_be_bytes());
+            body.extend_from_slice(&timestamp.to_be_bytes());
+            put_str(&mut body, author)?;
+            put_str(&mut body, text)?;
+        }
+        Op::User(username, since, presence) => {
+            put_str(&mut body, username)?;
+            body.extend_from_slice(&since.to_be_bytes());
+            body.push(*presence as u8);
+        }
+        Op::Leave(username) => put_str(&mut body, username)?,
+        Op::Direct {
+            id,
+            clock,
+            timestamp,
+            author,
+            recipient,
+            sealed,
+        } => {
+            body.extend_from_slice(&id.to_be_bytes());
+            body.extend_from_slice(&clock.to_be_bytes());
+            body.extend_from_slice(&timestamp.to_be_bytes());
+            put_str(&mut body, author)?;
+            body.extend_from_slice(recipient);
+            put_bytes(&mut body, sealed)?;
+        }
+        Op::Announce {
+            room,
+            label,
+            members,
+            passphrase,
+        } => {
+            body.extend_from_slice(room);
+            put_str(&mut body, label)?;
+            body.extend_from_slice(&members.to_be_bytes());
+            body.push(*passphrase as u8);
+        }
+        Op::Nack { sender, missing } => {
+            body.extend_from_slice(&sender.to_be_bytes());
+            let missing = &missing[..missing.len().min(u16::MAX as usize)];
+            body.extend_from_slice(&(missing.len() as u16).to_be_bytes());
+            for number in missing {
+                body.extend_from_slice(&number.to_be_bytes());
+            }
+        }
+        Op::Latest { number } => body.extend_from_slice(&number.to_be_bytes()),
+        Op::HistoryRequest { limit } => body.extend_from_slice(&limit.to_be_bytes()),
+        Op::History { requester, entries } => {
+            body.extend_from_slice(&requester.to_be_bytes());
+            let entries = &entries[..entries.len().min(u16::MAX as usize)];
+            body.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+            for entry in entries {
+                body.extend_from_slice(&entry.origin.to_be_bytes());
+                body.extend_from_slice(&entry.id.to_be_bytes());
+                body.extend_from_slice(&entry.clock.to_be_bytes());
+                body.extend_from_slice(&entry.timestamp.to_be_bytes());
+                put_str(&mut body, &entry.author)?;
+                put_str(&mut body, &entry.text)?;
+            }
+        }
+        Op::FileOffer {
+            transfer,
+            name,
+            size,
+            hash,
+            recipient,
+        } => {
+            body.extend_from_slice(&transfer.to_be_bytes());
+            put_str(&mut body, name)?;
+            body.extend_from_slice(&size.to_be_bytes());
+            body.extend_from_slice(hash);
+            match recipient {
+                Some(key) => {
+                    body.push(1);
+                    body.extend_from_slice(key);
+                }
+                None => body.push(0),
+            }
+        }
+        Op::FileAck {
+            sender,
+            transfer,
+            offset,
+            resend,
+        } => {
+            body.extend_from_slice(&sender.to_be_bytes());
+            body.extend_from_slice(&transfer.to_be_bytes());
+            body.extend_from_slice(&offset.to_be_bytes());
+            body.push(*resend as u8);
+        }
+        Op::FileChunk {
+            requester,
+            transfer,
+            offset,
+            hash,
+            data,
+        } => {
+            body.extend_from_slice(&requester.to_be_bytes());
+            body.extend_from_slice(&transfer.to_be_bytes());
+            body.extend_from_slice(&offset.to_be_bytes());
+            body.extend_from_slice(hash);
+            put_bytes(&mut body, data)?;
+        }
+    }
+    Ok(body)
+}
+
+pub fn decode(opcode: OpCode, body: &[u8]) -> Result<Packet, DecodeError> {
+    let mut reader = Reader::new(body);
+    let instance = reader.u64()?;
+    let sequence = reader.u64()?;
+    let timestamp = reader.u64()?;
+    let op = match opcode {
+        OpCode::Message => Op::Message {
+            id: reader.u64()?,
+            number: reader.u64()?,
+            clock: reader.u64()?,
+            timestamp: reader.u64()?,
+            author: reader.string()?,
+            text: reader.text()?,
+        },
+        OpCode::User => Op::User(
+            reader.string()?,
+            reader.u64()?,
+            Presence::try_from(reader.u8()?)?,
+        ),
+        OpCode::Leave => Op::Leave(reader.string()?),
+        OpCode::Direct => Op::Direct {
+            id: reader.u64()?,
+            clock: reader.u64()?,
+            timestamp: reader.u64()?,
+            author: reader.string()?,
+            recipient: reader.bytes(PUBLIC_KEY_LEN)?.try_into().unwrap(),
+            sealed: reader.blob()?.to_vec(),
+        },
+        OpCode::Announce => Op::Announce {
+            room: reader.bytes(ROOM_TAG_LEN)?.try_into().unwrap(),
+            label: reader.string()?,
+            members: reader.u16()?,
+            passphrase: reader.u8()? != 0,
+        },
+        OpCode::Nack => Op::Nack {
+            sender: reader.u64()?,
+            missing: {
+                let count = reader.u16()?;
+                (0..count).map(|_| reader.u64()).collect::<Result<_, _>>()?
+            },
+        },
+        OpCode::Latest => Op::Latest {
+            number: reader.u64()?,
+        },
+        OpCode::HistoryRequest => Op::HistoryRequest {
+            limit: reader.u16()?,
+        },
+        OpCode::History => Op::History {
+            requester: reader.u64()?,
+            entries: {
+                let count = reader.u16()?;
+                (0..count)
+                    .map(|_| {
+                        Ok(HistoryEntry {
+                            origin: reader.u64()?,
+                            id: reader.u64()?,
+                            clock: reader.u64()?,
+                            timestamp: reader.u64()?,
+                            author: reader.string()?,
+                            text: reader.text()?,
+                        })
+                    })
+                    .collect::<Result<_, DecodeError>>()?
+            },
+        },
+        OpCode::FileOffer => Op::FileOffer {
+            transfer: reader.u64()?,
+            name: reader.string()?,
+            size: reader.u64()?,
+            hash: reader.bytes(HASH_LEN)?.try_into().unwrap(),
+            recipient: match reader.u8()? {
+                0 => None,
+                _ => Some(reader.bytes(PUBLIC_KEY_LEN)?.try_into().unwrap()),
+            },
+        },
+        OpCode::FileAck => Op::FileAck {
+            sender: reader.u64()?,
+            transfer: reader.u64()?,
+            offset: reader.u64()?,
+            resend: reader.u8()? != 0,
+        },
+        OpCode::FileChunk => Op::FileChunk {
+            requester: reader.u64()?,
+            transfer: reader.u64()?,
+            offset: reader.u64()?,
+            hash: reader.bytes(HASH_LEN)?.try_into().unwrap(),
+            data: reader.blob()?.to_vec(),
+        },
+    };
+    reader.finish()?;
+    Ok(Packet {
+        instance,
+        sequence,
+        timestamp,
+        op,
+    })
+}
+
+pub fn append_signature(body: &mut Vec<u8>, key: &PublicKey, signature: &[u8; SIGNATURE_LEN]) {
+    body.extend_from_slice(key);
+    body.extend_from_slice(signature);
+}
+
+pub fn split_signature(
+    plaintext: &[u8],
+) -> Result<(&[u8], PublicKey, [u8; SIGNATURE_LEN]), DecodeError> {
+    let body_len = plaintext
+        .len()
+        .checked_sub(SIGNATURE_TRAILER_LEN)
+        .ok_or(DecodeError::Truncated)?;
+    let (body, trailer) = plaintext.split_at(body_len);
+    let (key, signature) = trailer.split_at(PUBLIC_KEY_LEN);
+    Ok((body, key.try_into().unwrap(), signature.try_into().unwrap()))
+}
+
+pub fn frame(flags: u8, opcode: OpCode, room: RoomTag, payload: &[u8]) -> Vec<u8> {
+    let header = Header::new(flags, opcode, room, payload.len());
+    let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len());
+    header.write(&mut datagram);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+pub fn unframe(datagram: &[u8]) -> Result<(Header, &[u8]), DecodeError> {
+    let header = Header::read(datagram)?;
+    let payload = &datagram[HEADER_LEN..];
+    if payload.len() != header.length as usize {
+        return Err(DecodeError::LengthMismatch);
+    }
+    Ok((header, payload))
+}
+
+// Payloads of the pieces, still to be signed and framed.
+pub fn fragment(datagram: &[u8], instance: u64, id: u64) -> Vec<Vec<u8>> {
+    let chunks = datagram.chunks(FRAGMENT_DATA_LEN);
+    let count = chunks.len() as u16;
+    chunks
+        .enumerate()
+        .map(|(index, data)| {
+            let mut payload =
+                Vec::with_capacity(FRAGMENT_HEADER_LEN + data.len() + SIGNATURE_TRAILER_LEN);
+            payload.extend_from_slice(&instance.to_be_bytes());
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&(index as u16).to_be_bytes());
+            payload.extend_from_slice(&count.to_be_bytes());
+            payload.extend_from_slice(data);
+            payload
+        })
+        .collect()
+}
+
+pub fn read_fragment(payload: &[u8]) -> Result<Fragment<'_>, DecodeError> {
+    let mut reader = Reader::new(payload);
+    let instance = reader.u64()?;
+    let id = reader.u64()?;
+    let index = reader.u16()?;
+    let count = reader.u16()?;
+    if index >= count || count as usize > MAX_FRAGMENTS {
+        return Err(DecodeError::BadFragment);
+    }
+    Ok(Fragment {
+        instance,
+        id,
+        index,
+        count,
+        data: reader.rest(),
+    })
+}
+
+impl Header {
+    // Callers check the length first, anything longer is fragmented or
+    // refused before it gets here.
+    pub fn new(flags: u8, opcode: OpCode, room: RoomTag, length: usize) -> Self {
+        debug_assert!(length <= u16::MAX as usize, "payload of {length} bytes");
+        Header {
+            version: VERSION,
+            flags,
+            opcode,
+            room,
+            length: length as u16,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        self.write(&mut buf);
+        buf
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC);
+        buf.push(self.version);
+        buf.push(self.flags);
+        buf.push(self.opcode as u8);
+        buf.extend_from_slice(&self.room);
+        buf.extend_from_slice(&self.length.to_be_bytes());
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        if reader
+            .bytes(MAGIC.len())
+            .map_err(|_| DecodeError::BadMagic)?
+            != MAGIC
+        {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let flags = reader.u8()?;
+        let opcode = OpCode::try_from(reader.u8()?)?;
+        let room = reader.bytes(ROOM_TAG_LEN)?.try_into().unwrap();
+        let length = reader.u16()?;
+        Ok(Header {
+            version,
+            flags,
+            opcode,
+            room,
+            length,
+        })
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<(), EncodeError> {
+    put_bytes(buf, s.as_bytes())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), EncodeError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| EncodeError::FieldTooLong)?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() < n {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn blob(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.blob()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn text(&mut self) -> Result<String, DecodeError> {
+        let text = self.string()?;
+        if text.len() > MAX_MESSAGE_LEN {
+            return Err(DecodeError::MessageTooLong);
+        }
+        Ok(text)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: RoomTag = *b"roomtag!";
+
+    fn packet(op: Op) -> Packet {
+        Packet {
+            instance: 0x0123_4567_89ab_cdef,
+            sequence: 42,
+            timestamp: 1_700_000_000_000,
+            op,
+        }
+    }
+
+    fn round_trip(op: Op) {
+        let packet = packet(op);
+        let body = encode(&packet).unwrap();
+        let datagram = frame(FLAG_ENCRYPTED, packet.op.opcode(), ROOM, &body);
+        let (header, payload) = unframe(&datagram).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.flags, FLAG_ENCRYPTED);
+        assert_eq!(header.opcode, packet.op.opcode());
+        assert_eq!(header.room, ROOM);
+        assert_eq!(header.length as usize, body.len());
+        assert_eq!(decode(header.opcode, payload).unwrap(), packet);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        round_trip(Op::Message {
+            id: 1,
+            number: 1,
+            clock: 1,
+            timestamp: 1_700_000_000_000,
+            author: "alice".to_string(),
+            text: "hi :3".to_string(),
+        });
+        round_trip(Op::Message {
+            id: u64::MAX,
+            number: u64::MAX,
+            clock: u64::MAX,
+            timestamp: 0,
+            author: "zażółć".to_string(),
+            text: String::new(),
+        });
+    }
+
+    #[test]
+    fn user_round_trip() {
+        round_trip(Op::User(
+            "bob".to_string(),
+            1_700_000_000_000,
+            Presence::Active,
+        ));
+        round_trip(Op::User(
+            "bob".to_string(),
+            1_700_000_000_000,
+            Presence::Idle,
+        ));
+    }
+
+    #[test]
+    fn leave_round_trip() {
+        round_trip(Op::Leave("carol".to_string()));
+    }
+
+    #[test]
+    fn direct_round_trip() {
+        round_trip(Op::Direct {
+            id: 3,
+            clock: 12,
+            timestamp: 1_700_000_000_000,
+            author: "alice".to_string(),
+            recipient: [5; PUBLIC_KEY_LEN],
+            sealed: vec![1, 2, 3, 4],
+        });
+    }
+
+    #[test]
+    fn announce_round_trip() {
+        round_trip(Op::Announce {
+            room: ROOM,
+            label: "lobby".to_string(),
+            members: 3,
+            passphrase: true,
+        });
+        round_trip(Op::Announce {
+            room: ROOM,
+            label: String::new(),
+            members: 0,
+            passphrase: false,
+        });
+    }
+
+    #[test]
+    fn reliability_round_trip() {
+        round_trip(Op::Nack {
+            sender: 0xfeed,
+            missing: vec![3, 4, 9],
+        });
+        round_trip(Op::Nack {
+            sender: 1,
+            missing: Vec::new(),
+        });
+        round_trip(Op::Latest { number: 12 });
+    }
+
+    #[test]
+    fn history_round_trip() {
+        round_trip(Op::HistoryRequest { limit: 50 });
+        round_trip(Op::History {
+            requester: 0xbeef,
+            entries: vec![
+                HistoryEntry {
+                    origin: 7,
+                    id: 1,
+                    clock: 5,
+                    timestamp: 1_700_000_000_000,
+                    author: "alice".to_string(),
+                    text: "first".to_string(),
+                },
+                HistoryEntry {
+                    origin: 8,
+                    id: 4,
+                    clock: 9,
+                    timestamp: 1_700_000_000_500,
+                    author: "bob".to_string(),
+                    text: String::new(),
+                },
+            ],
+        });
+        round_trip(Op::History {
+            requester: 1,
+            entries: Vec::new(),
+        });
+    }
+
+    #[test]
+    fn file_round_trip() {
+        round_trip(Op::FileOffer {
+            transfer: 0x5eed,
+            name: "build.log".to_string(),
+            size: 123_456,
+            hash: [3; HASH_LEN],
+            recipient: None,
+        });
+        round_trip(Op::FileOffer {
+            transfer: 1,
+            name: String::new(),
+            size: 0,
+            hash: [0; HASH_LEN],
+            recipient: Some([4; PUBLIC_KEY_LEN]),
+        });
+        round_trip(Op::FileAck {
+            sender: 0xfeed,
+            transfer: 0x5eed,
+            offset: 1800,
+            resend: true,
+        });
+        round_trip(Op::FileChunk {
+            requester: 0xbeef,
+            transfer: 0x5eed,
+            offset: 900,
+            hash: [6; HASH_LEN],
+            data: vec![1, 2, 3],
+        });
+    }
+
+    #[test]
+    fn fragments_reassemble() {
+        let body: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let datagram = frame(FLAG_ENCRYPTED, OpCode::Message, ROOM, &body);
+        let pieces = fragment(&datagram, 0x1234, 0xabcd);
+        assert_eq!(pieces.len(), datagram.len().div_ceil(FRAGMENT_DATA_LEN));
+        let mut whole = Vec::new();
+        for (i, mut payload) in pieces.into_iter().enumerate() {
+            append_signature(&mut payload, &[7; PUBLIC_KEY_LEN], &[9; SIGNATURE_LEN]);
+            let piece = frame(FLAG_FRAGMENT | FLAG_SIGNED, OpCode::Message, ROOM, &payload);
+            assert!(piece.len() <= MAX_DATAGRAM);
+            let (header, payload) = unframe(&piece).unwrap();
+            assert_eq!(header.flags, FLAG_FRAGMENT | FLAG_SIGNED);
+            assert_eq!(header.room, ROOM);
+            let (payload, _, _) = split_signature(payload).unwrap();
+            let fragment = read_fragment(payload).unwrap();
+            assert_eq!(fragment.instance, 0x1234);
+            assert_eq!(fragment.id, 0xabcd);
+            assert_eq!(fragment.index as usize, i);
+            assert_eq!(
+                fragment.count as usize,
+                datagram.len().div_ceil(FRAGMENT_DATA_LEN)
+            );
+            whole.extend_from_slice(fragment.data);
+        }
+        assert_eq!(whole, datagram);
+    }
+
+    #[test]
+    fn rejects_bad_fragments() {
+        let mut payload = [2u64.to_be_bytes(), 1u64.to_be_bytes()].concat();
+        payload.extend_from_slice(&[0, 3, 0, 3]);
+        assert_eq!(read_fragment(&payload), Err(DecodeError::BadFragment));
+        assert_eq!(read_fragment(&payload[..17]), Err(DecodeError::Truncated));
+        payload[16..20].copy_from_slice(&[0, 0, 0xff, 0xff]);
+        assert_eq!(read_fragment(&payload), Err(DecodeError::BadFragment));
+    }
+
+    #[test]
+    fn signature_trailer_round_trip() {
+        let mut body = encode(&packet(Op::Leave("carol".to_string()))).unwrap();
+        let plain = body.clone();
+        append_signature(&mut body, &[7; PUBLIC_KEY_LEN], &[9; SIGNATURE_LEN]);
+        assert_eq!(body.len(), plain.len() + SIGNATURE_TRAILER_LEN);
+        let (rest, key, signature) = split_signature(&body).unwrap();
+        assert_eq!(rest, plain.as_slice());
+        assert_eq!(key, [7; PUBLIC_KEY_LEN]);
+        assert_eq!(signature, [9; SIGNATURE_LEN]);
+        assert_eq!(
+            split_signature(&body[..SIGNATURE_TRAILER_LEN - 1]),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut datagram = frame(
+            0,
+            OpCode::User,
+            ROOM,
+            &encode(&packet(Op::User(
+                "a".to_string(),
+                1_700_000_000_000,
+                Presence::Active,
+            )))
+            .unwrap(),
+        );
+        datagram[0] ^= 0xff;
+        assert_eq!(unframe(&datagram), Err(DecodeError::BadMagic));
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_opcode() {
+        let mut datagram = frame(0, OpCode::User, ROOM, &[]);
+        datagram[4] = VERSION + 1;
+        assert_eq!(
+            unframe(&datagram),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+        let mut datagram = frame(0, OpCode::User, ROOM, &[]);
+        datagram[6] = 0xee;
+        assert_eq!(unframe(&datagram), Err(DecodeError::UnknownOpCode(0xee)));
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut datagram = frame(
+            0,
+            OpCode::User,
+            ROOM,
+            &encode(&packet(Op::User(
+                "dave".to_string(),
+                1_700_000_000_000,
+                Presence::Active,
+            )))
+            .unwrap(),
+        );
+        datagram.pop();
+        assert_eq!(unframe(&datagram), Err(DecodeError::LengthMismatch));
+        assert_eq!(unframe(&datagram[..4]), Err(DecodeError::Truncated));
+        assert_eq!(unframe(&datagram[..10]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let mut body = encode(&packet(Op::User(
+            "erin".to_string(),
+            1_700_000_000_000,
+            Presence::Idle,
+        )))
+        .unwrap();
+        body.push(0);
+        assert_eq!(decode(OpCode::User, &body), Err(DecodeError::TrailingBytes));
+        body.truncate(body.len() - 2);
+        body.push(7);
+        assert_eq!(
+            decode(OpCode::User, &body),
+            Err(DecodeError::UnknownPresence(7))
+        );
+        let body = encode(&packet(Op::Message {
+            id: 7,
+            number: 7,
+            clock: 7,
+            timestamp: 7,
+            author: "frank".to_string(),
+            text: "hello".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(
+            decode(OpCode::Message, &body[..body.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+        let mut body = vec![0; 24];
+        body.extend_from_slice(&[0, 2, 0xc3, 0x28]);
+        assert_eq!(decode(OpCode::Leave, &body), Err(DecodeError::InvalidUtf8));
+    }
+
+    #[test]
+    fn refuses_to_encode_long_fields() {
+        let longest = packet(Op::Leave("é".repeat(u16::MAX as usize / 2)));
+        let body = encode(&longest).unwrap();
+        assert_eq!(decode(OpCode::Leave, &body), Ok(longest));
+        assert_eq!(
+            encode(&packet(Op::Leave("é".repeat(u16::MAX as usize / 2 + 1)))),
+            Err(EncodeError::FieldTooLong)
+        );
+        let chunk = |data| Op::FileChunk {
+            requester: 7,
+            transfer: 7,
+            offset: 0,
+            hash: [0; HASH_LEN],
+            data,
+        };
+        assert!(encode(&packet(chunk(vec![0; u16::MAX as usize]))).is_ok());
+        assert_eq!(
+            encode(&packet(chunk(vec![0; u16::MAX as usize + 1]))),
+            Err(EncodeError::FieldTooLong)
+        );
+    }
+
+    #[test]
+    fn rejects_long_messages() {
+        let message = |text: String| Op::Message {
+            id: 7,
+            number: 7,
+            clock: 7,
+            timestamp: 7,
+            author: "grace".to_string(),
+            text,
+        };
+        round_trip(message("x".repeat(MAX_MESSAGE_LEN)));
+        let body = encode(&packet(message("x".repeat(MAX_MESSAGE_LEN + 1)))).unwrap();
+        assert_eq!(
+            decode(OpCode::Message, &body),
+            Err(DecodeError::MessageTooLong)
+        );
+        let body = encode(&packet(Op::History {
+            requester: 7,
+            entries: vec![HistoryEntry {
+                origin: 7,
+                id: 7,
+                clock: 7,
+                timestamp: 7,
+                author: "grace".to_string(),
+                text: "x".repeat(MAX_MESSAGE_LEN + 1),
+            }],
+        }))
+        .unwrap();
+        assert_eq!(
+            decode(OpCode::History, &body),
+            Err(DecodeError::MessageTooLong)
+        );
+    }
+}
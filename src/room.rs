@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Instant,
+};
+
+use crate::chat::Conversation;
+use crate::crypto::{RoomCrypto, RoomTag};
+use crate::network::OnlineUser;
+
+pub struct Room {
+    pub name: String,
+    pub crypto: RoomCrypto,
+    // Both keyed by the instance id of the sender, names are not unique.
+    pub users: Mutex<HashMap<u64, OnlineUser>>,
+    pub presence: Mutex<HashMap<u64, Instant>>,
+    pub chat: Conversation,
+    pub announce: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Announcement {
+    pub room: RoomTag,
+    pub label: String,
+    pub members: u16,
+    pub passphrase: bool,
+    pub expires: Instant,
+}
+
+pub type Rooms = Arc<Mutex<Vec<Arc<Room>>>>;
+pub type Lobby = Arc<Mutex<HashMap<RoomTag, Announcement>>>;
+
+// Room tags are in the clear, so anyone can make up as many as they like.
+const MAX_ANNOUNCED: usize = 256;
+
+impl Room {
+    pub fn new(name: String, crypto: RoomCrypto) -> Self {
+        Room {
+            name,
+            crypto,
+            users: Mutex::new(HashMap::new()),
+            presence: Mutex::new(HashMap::new()),
+            chat: Conversation::default(),
+            announce: AtomicBool::new(false),
+        }
+    }
+
+    // Adds the instance tag to names that someone else in the room uses too.
+    pub fn display_name(&self, instance: u64, username: &str) -> String {
+        if self.is_taken(username, instance) {
+            format!("{}{}", username, instance_tag(instance))
+        } else {
+            username.to_string()
+        }
+    }
+
+    pub fn members(&self) -> Vec<(String, OnlineUser)> {
+        let users = self.users.lock().unwrap();
+        let mut members: Vec<(String, OnlineUser)> = users
+            .iter()
+            .map(|(instance, user)| {
+                let shared = users
+                    .iter()
+                    .any(|(other, seen)| other != instance && seen.username == user.username);
+                let name = if shared {
+                    format!("{}{}", user.username, instance_tag(*instance))
+                } else {
+                    user.username.clone()
+                };
+                (name, user.clone())
+            })
+            .collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        members
+    }
+
+    // Whether anyone but `instance` goes by `username` here.
+    pub fn is_taken(&self, username: &str, instance: u64) -> bool {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(other, user)| *other != instance && user.username == username)
+    }
+}
+
+pub fn instance_tag(instance: u64) -> String {
+    format!("#{:04x}", instance >> 48)
+}
+
+pub fn find(rooms: &Rooms, tag: &[u8]) -> Option<Arc<Room>> {
+    rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|room| room.crypto.room_tag() == tag)
+        .cloned()
+}
+
+// Makes room for a new tag by dropping expired rooms, and when that is not
+// enough the one that would expire first.
+pub fn record(lobby: &Lobby, announcement: Announcement) {
+    let mut lobby = lobby.lock().unwrap();
+    let now = Instant::now();
+    lobby.retain(|_, seen| seen.expires > now);
+    if !lobby.contains_key(&announcement.room) && lobby.len() >= MAX_ANNOUNCED {
+        let first = lobby
+            .values()
+            .min_by_key(|seen| seen.expires)
+            .map(|seen| seen.room);
+        if let Some(first) = first {
+            lobby.remove(&first);
+        }
+    }
+    lobby.insert(announcement.room, announcement);
+}
+
+// Drops rooms that stopped announcing and returns the rest sorted by label.
+pub fn announced(lobby: &Lobby) -> Vec<Announcement> {
+    let mut lobby = lobby.lock().unwrap();
+    let now = Instant::now();
+    lobby.retain(|_, seen| seen.expires > now);
+    let mut rooms: Vec<Announcement> = lobby.values().cloned().collect();
+    rooms.sort_by(|a, b| a.label.cmp(&b.label).then(a.room.cmp(&b.room)));
+    rooms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn announcement(room: RoomTag, expires: Instant) -> Announcement {
+        Announcement {
+            room,
+            label: "room".to_string(),
+            members: 1,
+            passphrase: false,
+            expires,
+        }
+    }
+
+    #[test]
+    fn record_drops_expired_and_caps_the_lobby() {
+        let lobby = Lobby::default();
+        let now = Instant::now();
+        record(&lobby, announcement([0; 8], now - Duration::from_secs(1)));
+        let later = now + Duration::from_secs(60);
+        for n in 1..=MAX_ANNOUNCED as u64 + 10 {
+            let expires = later + Duration::from_millis(n);
+            record(&lobby, announcement(n.to_be_bytes(), expires));
+        }
+        let lobby = lobby.lock().unwrap();
+        assert_eq!(lobby.len(), MAX_ANNOUNCED);
+        assert!(!lobby.contains_key(&[0; 8]));
+        // The ones closest to expiring made room for the newest.
+        assert!(!lobby.contains_key(&1u64.to_be_bytes()));
+        assert!(lobby.contains_key(&(MAX_ANNOUNCED as u64 + 10).to_be_bytes()));
+    }
+
+    #[test]
+    fn record_replaces_the_same_room() {
+        let lobby = Lobby::default();
+        let expires = Instant::now() + Duration::from_secs(60);
+        record(&lobby, announcement([1; 8], expires));
+        let mut update = announcement([1; 8], expires);
+        update.members = 5;
+        record(&lobby, update);
+        let rooms = announced(&lobby);
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].members, 5);
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::chat::ChatMessage;
+use crate::identity::PublicKey;
+use crate::protocol::FileHash;
+use crate::room::Room;
+
+// Small enough that a sealed chunk with its signature still fits one datagram.
+pub const CHUNK_LEN: usize = 900;
+
+pub type Transfers = Arc<Mutex<Vec<Arc<Transfer>>>>;
+
+static NEXT_NUMBER: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    Offered = 0,
+    Active = 1,
+    Done = 2,
+    Failed = 3,
+    Declined = 4,
+}
+
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub hash: FileHash,
+}
+
+pub struct Transfer {
+    pub id: u64,
+    // What /accept and /decline call it, kept as expired offers go away.
+    pub number: usize,
+    // Instance id of whoever offered the file.
+    pub sender: u64,
+    // Only this key may fetch the file, chunks are sealed to it.
+    pub recipient: Option<PublicKey>,
+    pub room: Arc<Room>,
+    pub file: FileInfo,
+    // Who the file goes to or comes from.
+    pub peer: String,
+    pub progress: AtomicU64,
+    state: AtomicU8,
+    pub side: Side,
+}
+
+pub enum Side {
+    Upload(Mutex<Upload>),
+    Download(Mutex<Download>),
+}
+
+pub struct Upload {
+    pub file: fs::File,
+    pub offered: Instant,
+    // Heartbeats that repeated the offer, it stops once someone accepts.
+    pub repeats: u32,
+    pub streams: HashMap<u64, UploadStream>,
+    pub completed: usize,
+}
+
+// One recipient's progress: everything below `acked` has arrived, chunks up
+// to `next` are on their way.
+pub struct UploadStream {
+    pub acked: u64,
+    pub next: u64,
+    pub last_ack: Instant,
+    pub retries: u32,
+}
+
+pub struct Download {
+    pub sender_key: PublicKey,
+    // When the offer first arrived, unaccepted offers expire after a while.
+    pub offered: Instant,
+    pub file: Option<fs::File>,
+    pub part: PathBuf,
+    pub dir: PathBuf,
+    pub received: u64,
+    pub unacked: u32,
+    pub gap_reported: bool,
+    pub last_chunk: Instant,
+    pub retries: u32,
+}
+
+impl Transfer {
+    pub fn new(
+        id: u64,
+        sender: u64,
+        recipient: Option<PublicKey>,
+        room: Arc<Room>,
+        file: FileInfo,
+        peer: String,
+        side: Side,
+    ) -> Self {
+        Transfer {
+            id,
+            number: NEXT_NUMBER.fetch_add(1, Ordering::Relaxed),
+            sender,
+            recipient,
+            room,
+            file,
+            peer,
+            progress: AtomicU64::new(0),
+            state: AtomicU8::new(TransferState::Offered as u8),
+            side,
+        }
+    }
+
+    pub fn state(&self) -> TransferState {
+        match self.state.load(Ordering::Acquire) {
+            0 => TransferState::Offered,
+            1 => TransferState::Active,
+            2 => TransferState::Done,
+            3 => TransferState::Failed,
+            _ => TransferState::Declined,
+        }
+    }
+
+    pub fn set_state(&self, state: TransferState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn is_upload(&self) -> bool {
+        matches!(self.side, Side::Upload(_))
+    }
+
+    pub fn complete(&self, download: &mut Download) {
+        let text = match download.finish(&self.file) {
+            Ok(path) => {
+                self.set_state(TransferState::Done);
+                format!("saved {} to {}", self.file.name, path.display())
+            }
+            Err(err) => {
+                self.set_state(TransferState::Failed);
+                format!("failed to save {}: {err}", self.file.name)
+            }
+        };
+        self.room.chat.append(Arc::new(ChatMessage::system(text)));
+    }
+
+    pub fn fail(&self, reason: &str) {
+        self.set_state(TransferState::Failed);
+        self.room.chat.append(Arc::new(ChatMessage::system(format!(
+            "{}: {reason}",
+            self.file.name
+        ))));
+    }
+}
+
+impl Download {
+    pub fn new(sender_key: PublicKey) -> Self {
+        Download {
+            sender_key,
+            offered: Instant::now(),
+            file: None,
+            part: PathBuf::new(),
+            dir: PathBuf::new(),
+            received: 0,
+            unacked: 0,
+            gap_reported: false,
+            last_chunk: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    // Picks up a `.part` file left by an earlier attempt at the same file.
+    pub fn open(&mut self, dir: &Path, info: &FileInfo) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let part = dir.join(format!(".{}.{}.part", info.name, hex_prefix(&info.hash)));
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)?;
+        let mut received = file.metadata()?.len();
+        if received > info.size {
+            file.set_len(0)?;
+            received = 0;
+        }
+        file.seek(SeekFrom::End(0))?;
+        self.file = Some(file);
+        self.part = part;
+        self.dir = dir.to_path_buf();
+        self.received = received;
+        self.unacked = 0;
+        self.gap_reported = false;
+        self.last_chunk = Instant::now();
+        self.retries = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("download is not open"))?;
+        file.write_all(data)?;
+        self.received += data.len() as u64;
+        self.unacked += 1;
+        self.gap_reported = false;
+        self.last_chunk = Instant::now();
+        self.retries = 0;
+        Ok(())
+    }
+
+    // Checks the whole file against the offered hash and moves it next to
+    // the other downloads under a name that is not taken yet.
+    pub fn finish(&mut self, info: &FileInfo) -> std::io::Result<PathBuf> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        if hash_file(&self.part)?.1 != info.hash {
+            fs::remove_file(&self.part)?;
+            return Err(std::io::Error::other("the file does not match its hash"));
+        }
+        let path = free_path(&self.dir, &info.name);
+        fs::rename(&self.part, &path)?;
+        Ok(path)
+    }
+}
+
+pub fn find(transfers: &Transfers, sender: u64, id: u64) -> Option<Arc<Transfer>> {
+    transfers
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|transfer| transfer.sender == sender && transfer.id == id)
+        .cloned()
+}
+
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::download_dir().map(|dir| dir.join("hackchat"))
+}
+
+pub fn hash_file(path: &Path) -> std::io::Result<(u64, FileHash)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize().into()))
+}
+
+pub fn chunk_hash(data: &[u8]) -> FileHash {
+    Sha256::digest(data).into()
+}
+
+pub fn read_chunk(file: &mut fs::File, offset: u64) -> std::io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = Vec::with_capacity(CHUNK_LEN);
+    Read::take(&mut *file, CHUNK_LEN as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+// Offered names are only used as a file name inside the download directory.
+pub fn safe_name(name: &str) -> String {
+    let name: String = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "download".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn hex_prefix(hash: &FileHash) -> String {
+    hash[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::PUBLIC_KEY_LEN;
+    use crate::protocol::HASH_LEN;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("hackchat-transfer-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn safe_name_strips_paths() {
+        assert_eq!(safe_name("../x"), "x");
+        assert_eq!(safe_name("/etc/passwd"), "passwd");
+        assert_eq!(safe_name("a/b"), "b");
+        assert_eq!(safe_name("a\\b"), "b");
+        assert_eq!(safe_name(".hidden"), "hidden");
+        assert_eq!(safe_name("bell\x07.txt"), "bell.txt");
+        assert_eq!(safe_name(""), "download");
+        assert_eq!(safe_name(".."), "download");
+        assert_eq!(safe_name("dir/"), "download");
+    }
+
+    #[test]
+    fn free_path_numbers_taken_names() {
+        let dir = temp_dir("free");
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes.txt"));
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes (1).txt"));
+        fs::write(dir.join("notes (1).txt"), "").unwrap();
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+        fs::write(dir.join("notes"), "").unwrap();
+        assert_eq!(free_path(&dir, "notes"), dir.join("notes (1)"));
+    }
+
+    #[test]
+    fn download_resumes_part_file() {
+        let dir = temp_dir("resume");
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let source = dir.join("source");
+        fs::write(&source, &data).unwrap();
+        let (size, hash) = hash_file(&source).unwrap();
+        let info = FileInfo {
+            name: "file.bin".into(),
+            size,
+            hash,
+        };
+
+        let mut download = Download::new([0; PUBLIC_KEY_LEN]);
+        download.open(&dir, &info).unwrap();
+        assert_eq!(download.received, 0);
+        download.write(&data[..2000]).unwrap();
+        download.file = None;
+
+        let mut download = Download::new([0; PUBLIC_KEY_LEN]);
+        download.open(&dir, &info).unwrap();
+        assert_eq!(download.received, 2000);
+        download.write(&data[2000..]).unwrap();
+        let path = download.finish(&info).unwrap();
+        assert_eq!(path, dir.join("file.bin"));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!download.part.exists());
+    }
+
+    #[test]
+    fn download_restarts_oversized_part_file() {
+        let dir = temp_dir("oversized");
+        let info = FileInfo {
+            name: "file.bin".into(),
+            size: 10,
+            hash: [0; HASH_LEN],
+        };
+        let part = dir.join(format!(".file.bin.{}.part", hex_prefix(&info.hash)));
+        fs::write(&part, [0; 20]).unwrap();
+
+        let mut download = Download::new([0; PUBLIC_KEY_LEN]);
+        download.open(&dir, &info).unwrap();
+        assert_eq!(download.part, part);
+        assert_eq!(download.received, 0);
+        assert_eq!(fs::metadata(&part).unwrap().len(), 0);
+    }
+}
@@ -1,10 +1,19 @@
-use crate::app::{App, CurrentScreen, Inserting, Mode};
+use crate::app::{App, CurrentScreen, Inserting, Mode, View};
+use crate::chat::SendStatus;
+use crate::identity::{fingerprint, full_fingerprint};
+use crate::peers::Trust;
+use crate::protocol::{Presence, MAX_MESSAGE_LEN};
+use crate::room;
+use crate::transfer::{self, Transfer, TransferState};
 use ratatui::widgets::block::{Position, Title};
-use ratatui::widgets::{BorderType, Clear, List, ListItem, Paragraph};
+use ratatui::widgets::{BorderType, Clear, List, ListItem, Paragraph, Tabs};
 use ratatui::{prelude::*, widgets::Block};
+use std::sync::atomic::Ordering;
 
 const ONLINE_USERS_STR: &str = " Online users ";
 const BORDER_WIDTH: usize = 1;
+const STATUS_WIDTH: usize = 2;
+const TRANSFERS_SHOWN: usize = 6;
 
 impl App {
     pub fn ui(&mut self, frame: &mut Frame)
@@ -14,9 +23,31 @@ impl App {
         let [chat_window, online_users_window] =
             Layout::horizontal([Constraint::Percentage(65), Constraint::Percentage(35)])
                 .areas(frame.area());
+        let transfers = self.transfers.lock().unwrap().clone();
+        let shown = transfers.len().min(TRANSFERS_SHOWN);
+        let [online_users_window, transfers_window] = Layout::vertical([
+            Constraint::Percentage(100),
+            Constraint::Length(if shown == 0 { 0 } else { 2 * shown as u16 + 2 }),
+        ])
+        .areas(online_users_window);
+
+        if shown > 0 {
+            let block = Block::bordered().border_type(BorderType::Rounded).title(
+                Title::from(" Transfers ")
+                    .position(Position::Top)
+                    .alignment(Alignment::Center),
+            );
+            let width = (transfers_window.width as usize).saturating_sub(2 * BORDER_WIDTH);
+            let items: Vec<ListItem> = transfers
+                .iter()
+                .skip(transfers.len() - shown)
+                .map(|transfer| transfer_item(transfer.number, transfer, width))
+                .collect();
+            frame.render_widget(List::new(items).block(block), transfers_window);
+        }
 
         {
-            let online_users_block = Block::bordered()
+            let mut online_users_block = Block::bordered()
                 .style(Style::default())
                 .border_type(BorderType::Rounded)
                 .title(
@@ -25,29 +56,76 @@ impl App {
                         .alignment(Alignment::Center),
                 );
 
+            let foreign = self.dropped.foreign_room.load(Ordering::Relaxed);
+            let invalid = self.dropped.malformed.load(Ordering::Relaxed)
+                + self.dropped.undecryptable.load(Ordering::Relaxed);
+            let duplicate = self.dropped.duplicate.load(Ordering::Relaxed);
+            let unverified = self.dropped.unverified.load(Ordering::Relaxed);
+            let replayed = self.dropped.replayed.load(Ordering::Relaxed)
+                + self.dropped.stale.load(Ordering::Relaxed);
+            if foreign + invalid + duplicate + unverified + replayed > 0 {
+                online_users_block = online_users_block.title(
+                    Title::from(format!(
+                        " dropped: {} other room, {} invalid, {} duplicate, {} unverified, {} replayed ",
+                        foreign, invalid, duplicate, unverified, replayed
+                    ))
+                    .position(Position::Bottom)
+                    .alignment(Alignment::Center),
+                );
+            }
+
             let mut list_items: Vec<ListItem> = Vec::new();
-            for username in &*self.online_users.lock().unwrap() {
-                list_items.push(ListItem::new(Text::from(format!("> {}", username.clone()))));
+            if let Some(room) = self.current_room() {
+                online_users_block = online_users_block.title(
+                    Title::from(format!(" {} ", room.name))
+                        .position(Position::Top)
+                        .alignment(Alignment::Left),
+                );
+                for (username, user) in room.members() {
+                    let mark = user.authenticity.mark();
+                    let item = match user.presence {
+                        Presence::Active => format!("> {}{}", username, mark),
+                        Presence::Idle => format!("> {}{} (idle)", username, mark),
+                    };
+                    list_items.push(ListItem::new(Text::from(item)));
+                }
             }
             let usernames_list = List::new(list_items).block(online_users_block);
             frame.render_widget(usernames_list, online_users_window);
         }
 
-        let [messages_box, chat_input] =
-            Layout::vertical([Constraint::Percentage(100), Constraint::Min(3)]).areas(chat_window);
+        let [tabs_bar, messages_box, chat_input] = Layout::vertical([
+            Constraint::Length((self.view_count() > 1) as u16),
+            Constraint::Percentage(100),
+            Constraint::Min(3),
+        ])
+        .areas(chat_window);
 
-        self.max_chat_index = self
-            .chat_messages
+        let view = self.active_view();
+        match &view {
+            View::Room(room) => room.chat.mark_read(),
+            View::Direct(direct) => direct.chat.mark_read(),
+            View::System => {}
+        }
+        let chat_messages = self.active_chat().1;
+        self.max_chat_index = chat_messages
             .lock()
             .unwrap()
             .1
             .len()
-            .checked_sub(messages_box.height as usize - BORDER_WIDTH)
-            .unwrap_or(0);
+            .saturating_sub(messages_box.height as usize - BORDER_WIDTH);
         {
-            let chat_input_block = Block::bordered()
+            let mut chat_input_block = Block::bordered()
                 .style(Style::default())
                 .border_type(BorderType::Rounded);
+            if self.input_too_long {
+                chat_input_block = chat_input_block
+                    .border_style(Style::default().fg(Color::Red))
+                    .title(
+                        Title::from(format!(" messages are limited to {MAX_MESSAGE_LEN} bytes "))
+                            .alignment(Alignment::Right),
+                    );
+            }
 
             let para = Paragraph::new(self.chat_input.clone()).block(chat_input_block);
             frame.render_widget(para, chat_input);
@@ -63,43 +141,91 @@ impl App {
                 .border_type(BorderType::Rounded);
 
             if let Some(username) = &self.username {
+                let title = match &self.identity {
+                    Some(identity) => {
+                        format!("{} ✓{}", username, fingerprint(&identity.public_key()))
+                    }
+                    None => username.clone(),
+                };
                 messages_box_block = messages_box_block.title(
-                    Title::from(username.clone())
+                    Title::from(title)
                         .alignment(Alignment::Left)
                         .position(Position::Top),
                 );
             }
 
-            if let Some(room_name) = &self.room_name {
-                messages_box_block = messages_box_block.title(
-                    Title::from(format!(" {} ", room_name))
+            match &view {
+                View::Direct(direct) => {
+                    messages_box_block = messages_box_block.title(
+                        Title::from(format!(
+                            " direct: {} {} ",
+                            direct.peer,
+                            fingerprint(&direct.key)
+                        ))
                         .alignment(Alignment::Center)
                         .position(Position::Top),
-                );
+                    );
+                }
+                View::Room(room) => {
+                    let mut title = vec![Span::raw(format!(" {} ", room.name))];
+                    if !room.crypto.is_encrypted() {
+                        title.push(Span::styled(
+                            "[unencrypted] ",
+                            Style::default().fg(Color::Red),
+                        ));
+                    }
+                    messages_box_block = messages_box_block.title(
+                        Title::from(Line::from(title))
+                            .alignment(Alignment::Center)
+                            .position(Position::Top),
+                    );
+                }
+                View::System => {}
+            }
+
+            if self.view_count() > 1 {
+                let rooms = self.rooms.lock().unwrap().clone();
+                let directs = self.directs.lock().unwrap().clone();
+                let titles = rooms
+                    .iter()
+                    .map(|room| tab_title(&room.name, room.chat.unread()))
+                    .chain(directs.iter().map(|direct| {
+                        tab_title(&format!("@{}", direct.peer), direct.chat.unread())
+                    }));
+                let tabs = Tabs::new(titles)
+                    .select(self.view)
+                    .padding("", "")
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+                frame.render_widget(tabs, tabs_bar);
             }
 
             let mut messages_list: Vec<ListItem> = Vec::new();
-            if self.chat_messages.lock().unwrap().0
-                != (messages_box.width as usize).saturating_sub(BORDER_WIDTH)
+            if chat_messages.lock().unwrap().0
+                != (messages_box.width as usize).saturating_sub(BORDER_WIDTH + STATUS_WIDTH)
             {
-                self.create_lines((messages_box.width as usize).saturating_sub(BORDER_WIDTH));
+                self.create_lines(
+                    (messages_box.width as usize).saturating_sub(BORDER_WIDTH + STATUS_WIDTH),
+                );
             }
 
             let start = self.chat_index.clamp(
                 0,
-                self.chat_messages
+                chat_messages
                     .lock()
                     .unwrap()
                     .1
                     .len()
-                    .checked_sub(messages_box.height as usize - BORDER_WIDTH)
-                    .unwrap_or(0),
+                    .saturating_sub(messages_box.height as usize - BORDER_WIDTH),
             );
             let end = (start + messages_box.height as usize - BORDER_WIDTH)
-                .clamp(start, self.chat_messages.lock().unwrap().1.len());
+                .clamp(start, chat_messages.lock().unwrap().1.len());
 
-            for s in &self.chat_messages.lock().unwrap().1[start..end] {
-                messages_list.push(ListItem::new(Text::from(s.clone())));
+            for line in &chat_messages.lock().unwrap().1[start..end] {
+                let mut spans = vec![Span::raw(line.text.clone())];
+                if let Some(message) = &line.message {
+                    spans.push(status_span(message.status()));
+                }
+                messages_list.push(ListItem::new(Line::from(spans)));
             }
 
             let list = List::new(messages_list).block(messages_box_block);
@@ -108,7 +234,7 @@ impl App {
 
         match self.current_screen {
             CurrentScreen::Login => {
-                let window = centered_rect(50, 20, frame.area());
+                let window = centered_rect(70, 40, frame.area());
                 let enter_block = Block::bordered()
                     .border_type(BorderType::Rounded)
                     .style(Style::default().bg(Color::Black))
@@ -122,17 +248,28 @@ impl App {
                         Title::default()
                             .alignment(Alignment::Center)
                             .position(Position::Bottom)
-                            .content(" Press <Tab> to switch fields, <Enter> to submit "),
+                            .content(
+                                " <Tab> switch fields, <Up>/<Down> pick a room or interface, <Enter> submit ",
+                            ),
                     );
                 let inner = enter_block.inner(window);
-                let [_, username_rect, _, room_rect, _] = Layout::vertical([
-                    Constraint::Percentage(23),
-                    Constraint::Min(3),
-                    Constraint::Percentage(45),
-                    Constraint::Min(3),
-                    Constraint::Percentage(23),
-                ])
-                .areas(inner);
+                let [form, lobby_rect] =
+                    Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                        .areas(inner);
+                let [_, username_rect, _, room_rect, _, passphrase_rect, _, encryption_rect, interface_rect, _] =
+                    Layout::vertical([
+                        Constraint::Percentage(10),
+                        Constraint::Min(3),
+                        Constraint::Percentage(10),
+                        Constraint::Min(3),
+                        Constraint::Percentage(10),
+                        Constraint::Min(3),
+                        Constraint::Percentage(10),
+                        Constraint::Length(1),
+                        Constraint::Length(1),
+                        Constraint::Percentage(10),
+                    ])
+                    .areas(form);
                 frame.render_widget(enter_block, window);
                 let username_block = Block::bordered().border_type(BorderType::Rounded).title(
                     Title::default()
@@ -147,21 +284,220 @@ impl App {
                         .alignment(Alignment::Center)
                         .content(" Room name "),
                 );
+                let passphrase_block = Block::bordered().border_type(BorderType::Rounded).title(
+                    Title::default()
+                        .position(Position::Top)
+                        .alignment(Alignment::Center)
+                        .content(" Passphrase "),
+                );
                 let username_input =
                     Paragraph::new(self.username_input.as_str()).block(username_block);
                 let room_input = Paragraph::new(self.room_input.as_str()).block(room_block);
+                let passphrase_input =
+                    Paragraph::new("*".repeat(self.passphrase_input.chars().count()))
+                        .block(passphrase_block);
+                let encryption = if let Some(error) = &self.login_error {
+                    Span::styled(format!(" {error} "), Style::default().fg(Color::Red))
+                } else if let Some((username, room)) = &self.name_taken {
+                    Span::styled(
+                        format!(
+                            " {} is already used in {}, pick another name or <Enter> to keep it ",
+                            username, room
+                        ),
+                        Style::default().fg(Color::Red),
+                    )
+                } else if self.passphrase_input.is_empty() {
+                    Span::styled(
+                        " No passphrase: the room will be UNENCRYPTED ",
+                        Style::default().fg(Color::Red),
+                    )
+                } else {
+                    Span::styled(
+                        " Messages are encrypted with the passphrase ",
+                        Style::default().fg(Color::Green),
+                    )
+                };
+
+                let lobby_block = Block::bordered().border_type(BorderType::Rounded).title(
+                    Title::default()
+                        .position(Position::Top)
+                        .alignment(Alignment::Center)
+                        .content(" Rooms on the network "),
+                );
+                let mut lobby_items = Vec::new();
+                for seen in room::announced(&self.lobby) {
+                    let (access, color) = if seen.passphrase {
+                        ("passphrase", Color::Green)
+                    } else {
+                        ("open", Color::Red)
+                    };
+                    let mut item = ListItem::new(Line::from(vec![
+                        Span::raw(format!(" {} ({}) ", seen.label, seen.members)),
+                        Span::styled(access, Style::default().fg(color)),
+                    ]));
+                    if seen.label == self.room_input {
+                        item = item.style(Style::default().bg(Color::DarkGray));
+                    }
+                    lobby_items.push(item);
+                }
+                if lobby_items.is_empty() {
+                    lobby_items.push(ListItem::new(" No rooms announced yet"));
+                }
 
                 frame.render_widget(Clear, inner);
+                frame.render_widget(List::new(lobby_items).block(lobby_block), lobby_rect);
                 frame.render_widget(username_input, username_rect);
                 frame.render_widget(room_input, room_rect);
+                frame.render_widget(passphrase_input, passphrase_rect);
+                frame.render_widget(Paragraph::new(encryption).centered(), encryption_rect);
+                frame.render_widget(
+                    Paragraph::new(format!(" {} ", self.transport_label())).centered(),
+                    interface_rect,
+                );
                 let input_area = match self.inserting {
                     Inserting::Username => username_rect,
                     Inserting::Room => room_rect,
+                    Inserting::Passphrase => passphrase_rect,
                     Inserting::Chat => panic!("inserting chat while in login screen"),
                 };
                 frame.set_cursor_position(self.cursor_pos(input_area, self.inserting))
             }
             CurrentScreen::Main => {}
+            CurrentScreen::Join => {
+                let window = centered_rect(50, 30, frame.area());
+                let block = Block::bordered()
+                    .border_type(BorderType::Rounded)
+                    .style(Style::default().bg(Color::Black))
+                    .title(
+                        Title::default()
+                            .alignment(Alignment::Center)
+                            .position(Position::Top)
+                            .content(" Join a room "),
+                    )
+                    .title(
+                        Title::default()
+                            .alignment(Alignment::Center)
+                            .position(Position::Bottom)
+                            .content(" <Tab> switch fields, <Enter> join, <Esc> cancel "),
+                    );
+                let inner = block.inner(window);
+                let [_, room_rect, _, passphrase_rect, encryption_rect, _] = Layout::vertical([
+                    Constraint::Percentage(10),
+                    Constraint::Min(3),
+                    Constraint::Percentage(10),
+                    Constraint::Min(3),
+                    Constraint::Length(1),
+                    Constraint::Percentage(10),
+                ])
+                .areas(inner);
+                let room_block = Block::bordered().border_type(BorderType::Rounded).title(
+                    Title::default()
+                        .position(Position::Top)
+                        .alignment(Alignment::Center)
+                        .content(" Room name "),
+                );
+                let passphrase_block = Block::bordered().border_type(BorderType::Rounded).title(
+                    Title::default()
+                        .position(Position::Top)
+                        .alignment(Alignment::Center)
+                        .content(" Passphrase "),
+                );
+                let room_input = Paragraph::new(self.room_input.as_str()).block(room_block);
+                let passphrase_input =
+                    Paragraph::new("*".repeat(self.passphrase_input.chars().count()))
+                        .block(passphrase_block);
+                let encryption = if self.passphrase_input.is_empty() {
+                    Span::styled(
+                        " No passphrase: the room will be UNENCRYPTED ",
+                        Style::default().fg(Color::Red),
+                    )
+                } else {
+                    Span::styled(
+                        " Messages are encrypted with the passphrase ",
+                        Style::default().fg(Color::Green),
+                    )
+                };
+
+                frame.render_widget(Clear, window);
+                frame.render_widget(block, window);
+                frame.render_widget(room_input, room_rect);
+                frame.render_widget(passphrase_input, passphrase_rect);
+                frame.render_widget(Paragraph::new(encryption).centered(), encryption_rect);
+                let input_area = match self.inserting {
+                    Inserting::Passphrase => passphrase_rect,
+                    _ => room_rect,
+                };
+                frame.set_cursor_position(self.cursor_pos(input_area, self.inserting))
+            }
+            CurrentScreen::Fingerprints => {
+                let window = centered_rect(70, 70, frame.area());
+                let block = Block::bordered()
+                    .border_type(BorderType::Rounded)
+                    .style(Style::default().bg(Color::Black))
+                    .title(
+                        Title::default()
+                            .alignment(Alignment::Center)
+                            .position(Position::Top)
+                            .content(" Fingerprints "),
+                    )
+                    .title(
+                        Title::default()
+                            .alignment(Alignment::Center)
+                            .position(Position::Bottom)
+                            .content(
+                                " <Up>/<Down> select, <v> verified, <u> not verified, <Esc> back ",
+                            ),
+                    );
+                let [own_rect, peers_rect] =
+                    Layout::vertical([Constraint::Length(3), Constraint::Fill(1)])
+                        .areas(block.inner(window));
+
+                let own = match &self.identity {
+                    Some(identity) => full_fingerprint(&identity.public_key()),
+                    None => "no identity loaded".to_string(),
+                };
+                let own = Paragraph::new(vec![
+                    Line::from(" Your fingerprint:"),
+                    Line::from(format!("   {}", own)),
+                ]);
+
+                let known_peers = self.known_peers.lock().unwrap();
+                let mut items = Vec::new();
+                for (i, (username, key, trust)) in self.fingerprint_peers().iter().enumerate() {
+                    let (label, color) = match trust {
+                        Trust::Verified => ("verified", Color::Green),
+                        Trust::Pinned => ("not verified", Color::Yellow),
+                        Trust::Changed => ("KEY CHANGED", Color::Red),
+                    };
+                    let mut lines = vec![
+                        Line::from(vec![
+                            Span::raw(format!(" > {} ", username)),
+                            Span::styled(label, Style::default().fg(color)),
+                        ]),
+                        Line::from(format!("   {}", full_fingerprint(key))),
+                    ];
+                    if let (Trust::Changed, Some(pinned)) = (trust, known_peers.get(username)) {
+                        lines.push(Line::from(format!(
+                            "   was {}",
+                            full_fingerprint(&pinned.key)
+                        )));
+                    }
+                    let mut item = ListItem::new(Text::from(lines));
+                    if i == self.peer_index {
+                        item = item.style(Style::default().bg(Color::DarkGray));
+                    }
+                    items.push(item);
+                }
+                drop(known_peers);
+                if items.is_empty() {
+                    items.push(ListItem::new(" No signed users online"));
+                }
+
+                frame.render_widget(Clear, window);
+                frame.render_widget(block, window);
+                frame.render_widget(own, own_rect);
+                frame.render_widget(List::new(items), peers_rect);
+            }
             CurrentScreen::Quit => {
                 let [_, window, _] = Layout::vertical([
                     Constraint::Fill(1),
@@ -188,6 +524,58 @@ impl App {
     }
 }
 
+fn tab_title(name: &str, unread: usize) -> String {
+    match unread {
+        0 => format!(" {} ", name),
+        n => format!(" {} ({}) ", name, n),
+    }
+}
+
+// Two lines per transfer: what and with whom, then a progress bar or how it
+// ended.
+fn transfer_item(n: usize, transfer: &Transfer, width: usize) -> ListItem<'static> {
+    let size = transfer.file.size;
+    let done = transfer.progress.load(Ordering::Relaxed).min(size);
+    let direction = if transfer.is_upload() { "↑" } else { "↓" };
+    let title = format!(
+        "{n} {direction} {} ({}) {}",
+        transfer.file.name,
+        transfer::format_size(size),
+        transfer.peer
+    );
+    let status = match transfer.state() {
+        TransferState::Offered if !transfer.is_upload() => Span::styled(
+            format!("  /accept {n} or /decline {n}"),
+            Style::default().fg(Color::Yellow),
+        ),
+        TransferState::Offered | TransferState::Active => {
+            let percent = (done * 100).checked_div(size).unwrap_or(100);
+            let bar = width.saturating_sub(8);
+            let filled = (done as usize * bar)
+                .checked_div(size as usize)
+                .unwrap_or(bar);
+            Span::raw(format!(
+                "  {}{} {percent:>3}%",
+                "█".repeat(filled),
+                "░".repeat(bar - filled)
+            ))
+        }
+        TransferState::Done => Span::styled("  done", Style::default().fg(Color::Green)),
+        TransferState::Failed => Span::styled("  failed", Style::default().fg(Color::Red)),
+        TransferState::Declined => Span::styled("  declined", Style::default().fg(Color::DarkGray)),
+    };
+    ListItem::new(Text::from(vec![Line::from(title), Line::from(status)]))
+}
+
+fn status_span(status: SendStatus) -> Span<'static> {
+    match status {
+        SendStatus::Pending => Span::styled(" …", Style::default().fg(Color::DarkGray)),
+        SendStatus::Sent => Span::styled(" ✓", Style::default().fg(Color::Green)),
+        SendStatus::Failed => Span::styled(" ✗", Style::default().fg(Color::Red)),
+        SendStatus::Received => Span::raw(""),
+    }
+}
+
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     let popup_layout = Layout::default()
         .direction(Direction::Vertical)